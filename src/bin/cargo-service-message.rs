@@ -1,14 +1,16 @@
 use serde_json::{Deserializer, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{ChildStderr, Command, Stdio};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 fn main() -> Result<(), String> {
     //Setup interrupt handling (TODO: not sure this is actually responding to teamcity stop events?)
@@ -28,10 +30,12 @@ fn main() -> Result<(), String> {
     let options: Vec<String> = std::env::args().collect();
     println!("{:?}", &options);
 
-    if let Ok(exit_code) = cargo_service_message(options) {
-        std::process::exit(exit_code);
-    } else {
-        std::process::exit(-1);
+    match cargo_service_message(options) {
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(message) => {
+            eprintln!("cargo-service-message: {}", message);
+            std::process::exit(-1);
+        }
     }
 }
 
@@ -46,8 +50,12 @@ fn cargo_service_message(argv: Vec<String>) -> Result<i32, String> {
         return Err(format!("expected 'service-message' as the next argument followed by the standard cargo test arguments but got {}", argv[1]));
     }
 
-    let exit_code = run_cargo(&argv[2..]).unwrap();
-    Ok(exit_code)
+    if argv.get(2).map(String::as_str) == Some("coverage") {
+        run_coverage_command(&argv[3..])
+    } else {
+        run_cargo(&argv[2..])
+    }
+    .map_err(|err| err.to_string())
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -69,55 +77,120 @@ fn cargo_home() -> Result<String, std::env::VarError> {
     })
 }
 
+/// The user's cargo arguments plus those for json output that can be parsed.
+fn cargo_args(args: &[String], colors: bool) -> Vec<String> {
+    let cargo_cmd = &args[0];
+    let mut cargo_args = args.to_vec();
+
+    //Even though cargo clean doesn't do json at the moment it would be good if
+    // adding service-message was a no_op.
+    if cargo_cmd != "clean" && cargo_cmd != "fmt" {
+        cargo_args.push(format!(
+            "--message-format={}",
+            if colors {
+                "json-diagnostic-rendered-ansi"
+            } else {
+                "json"
+            }
+        )); //TODO: this needs to be before --
+        cargo_args.push("-Ztimings=json,html,info".to_string());
+        //TODO: this creates cargo-timings.html and friends in the root - we should move those to the target dir.
+    }
+
+    if !contains("--", args) {
+        cargo_args.push("--".to_string());
+    }
+
+    if cargo_cmd == "test" || cargo_cmd == "bench" {
+        if !contains("-Zunstable-options", args) && !contains("unstable-options", args) {
+            cargo_args.push("-Zunstable-options".to_string());
+        }
+        cargo_args.push("--format".to_string());
+        cargo_args.push("json".to_string());
+        // Without it libtest leaves out each test's `exec_time`.
+        if !contains("--report-time", args) {
+            cargo_args.push("--report-time".to_string());
+        }
+    }
+    cargo_args
+}
+
 fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
     //Params:
     let params = std::env::var("SERVICE_MESSAGE").unwrap_or_else(|_| "".into());
 
     let debug = params.contains("--debug");
-    let mut coverage = params.contains("--cover");
-    let coverage_no_report = params.contains("--cover-without-report");
+    let tap = param(&params, "--tap").map(|path| report_path(path, "test-results.tap"));
+    let codeclimate =
+        param(&params, "--codeclimate").map(|path| report_path(path, "codeclimate.json"));
+    let checkstyle = param(&params, "--checkstyle").map(|path| report_path(path, "checkstyle.xml"));
+    let summary =
+        param(&params, "--summary").map(|path| report_path(path, "service-message-summary.json"));
+    let drop_external = params.contains("--drop-external");
+    let fixes = param(&params, "--fixes").map(|path| report_path(path, "fixes.patch"));
+    let roots = SourceRoots::detect();
+    let baseline = match param(&params, "--baseline") {
+        Some(path) => {
+            let path = baseline_path(path, &roots.workspace);
+            match Baseline::load(&path) {
+                Ok(baseline) => Some(baseline),
+                Err(err) => {
+                    println!(
+                        "cargo-service-message: no baseline read from {}: {}",
+                        path.display(),
+                        err
+                    );
+                    Some(Baseline::default())
+                }
+            }
+        }
+        None => None,
+    };
+    let write_baseline =
+        param(&params, "--write-baseline").map(|path| baseline_path(path, &roots.workspace));
+    let severities = parse_severities(param(&params, "--severity").unwrap_or(""));
+    let policy = parse_policy(param(&params, "--policy").unwrap_or(""));
+    let report = param(&params, "--report").map(|path| report_path(path, "service-message-report"));
+    // Between `coverage start` and `coverage report` everything is instrumented and nothing reported.
+    let coverage_session = active_coverage_session(&coverage_data_dir());
+    let mut coverage = param(&params, "--cover").is_some() || coverage_session;
+    let coverage_no_report = param(&params, "--cover-without-report").is_some() || coverage_session;
+    let coverage_options = CoverageOptions::parse(&params, &roots.workspace);
 
     let cargo_cmd = &args[0]; //TODO: support +nightly
 
-    if coverage && cargo_cmd == "test" {
-        if Command::new("grcov").arg("--version").output().is_err() {
+    let instrumentable =
+        cargo_cmd == "test" || (coverage_session && (cargo_cmd == "run" || cargo_cmd == "build"));
+    if coverage_session && instrumentable {
+        println!(
+            "coverage: cargo {} is instrumented as a coverage session is active ('coverage report' ends it)",
+            cargo_cmd
+        );
+    }
+    if coverage && instrumentable {
+        if let Err(missing) = coverage_options.engine.check() {
             coverage = false;
-            println!("cargo-service-message: grcov not found on path so no coverage. (cargo install grcov?)");
+            println!("cargo-service-message: {} so no coverage.", missing);
         } else {
-            println!("testing with coverage...");
-            let _clean_done = Command::new("cargo").arg("clean").status();
+            println!("running cargo {} with coverage...", cargo_cmd);
+            start_coverage_run(&coverage_data_dir())?;
         }
     } else if cargo_cmd == "test" {
         println!("testing without coverage (set SERVICE_MESSAGE=--cover for coverage)");
     }
-    if coverage && cargo_cmd != "test" {
+    if coverage && !instrumentable {
         coverage = false;
     }
 
-    let colors = false; //TODO: wait for teamcity inspections to understand ansi
-                        //Also TODO: replace ansi yellow => orange as yellow on white unreadable unless in darkmode!
+    // The build log gets colour; inspections get the plain text.
+    let colors = !params.contains("--no-color");
     let brand = std::env::var("SERVICE_BRAND").unwrap_or_else(|_| "teamcity".to_owned());
     let min_threshold = 5.; // Any crate that compiles faster than this many seconds won't be tracked via statistics.
 
     let mut cmd = Command::new("cargo");
     cmd.stderr(Stdio::inherit());
     cmd.stdout(Stdio::piped());
-    cmd.args(args);
-
-    //Even though cargo clean doesn't do json at the moment it would be good if
-    // adding service-message was a no_op.
-    if cargo_cmd != "clean" && cargo_cmd != "fmt" {
-        cmd.arg(format!(
-            "--message-format={}",
-            if colors {
-                "json-diagnostic-rendered-ansi"
-            } else {
-                "json"
-            }
-        )); //TODO: this needs to be before --
-        cmd.arg("-Ztimings=json,html,info");
-        //TODO: this creates cargo-timings.html and friends in the root - we should move those to the target dir.
-    }
+    cmd.args(cargo_args(args, colors));
 
     let mode = if contains("--release", args) {
         "release"
@@ -125,40 +198,98 @@ fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
         "debug"
     };
 
-    if !contains("--", args) {
-        cmd.arg("--");
-    }
-
-    if cargo_cmd == "test" || cargo_cmd == "bench" {
-        if !contains("-Zunstable-options", args) && !contains("unstable-options", args) {
-            cmd.arg("-Zunstable-options");
-        }
-        cmd.arg("--format");
-        cmd.arg("json");
-    }
-
-    if coverage && (cargo_cmd == "test" || cargo_cmd == "build") {
-        // TODO: maybe support -Zexperimental-coverage
-
+    if coverage {
         let flags = env::var("RUSTFLAGS").unwrap_or_else(|_| "".to_string());
         // TODO: dedup flags if already set
 
         //TODO: can't use -Zpanic_abort_tests -Cpanic=abort as not compatible with proc macros!!!
-        cmd.env("RUSTFLAGS", format!("{} -Zinstrument-coverage -Zprofile -Ccodegen-units=1 -Copt-level=0 -Clink-dead-code -Coverflow-checks=off ", flags));
-        cmd.env("CARGO_INCREMENTAL", "0");
-        cmd.env("RUSTDOCFLAGS", "-Cpanic=abort");
+        // `--cfg coverage_nightly` lets code opt out with `#[cfg_attr(coverage_nightly, coverage(off))]`.
+        let version = rustc_version();
+        let nightly = is_nightly(&version);
+        let mut cfgs = if nightly {
+            "--cfg coverage --cfg coverage_nightly"
+        } else {
+            "--cfg coverage"
+        }
+        .to_string();
+        if has_check_cfg(&version) {
+            // Declared so that `#[cfg(coverage)]` doesn't set off the `unexpected_cfgs` lint.
+            cfgs.push_str(" --check-cfg=cfg(coverage,coverage_nightly)");
+        }
+        cmd.env(
+            "RUSTFLAGS",
+            format!("{} -Cinstrument-coverage {}", flags, cfgs),
+        );
+        // Instrumented artifacts live apart from normal ones so neither
+        // build invalidates the other and coverage builds stay incremental.
+        cmd.env("CARGO_TARGET_DIR", coverage_target_dir());
+        // Each process writes its own profile (%p) per instrumented binary (%m).
+        cmd.env(
+            "LLVM_PROFILE_FILE",
+            coverage_data_dir().join("%p-%m.profraw"),
+        );
+        if nightly {
+            // Doctest binaries are normally deleted once run; keeping them lets
+            // llvm-cov map their profiles back to source.
+            let doc_flags = env::var("RUSTDOCFLAGS").unwrap_or_else(|_| "".to_string());
+            cmd.env(
+                "RUSTDOCFLAGS",
+                format!(
+                    "{} -Cinstrument-coverage {} -Zunstable-options --persist-doctests {}",
+                    doc_flags,
+                    cfgs,
+                    doctests_dir().display()
+                ),
+            );
+        } else {
+            let doc_flags = env::var("RUSTDOCFLAGS").unwrap_or_else(|_| "".to_string());
+            cmd.env("RUSTDOCFLAGS", format!("{} -Cpanic=abort", doc_flags));
+            if cargo_cmd == "test" {
+                println!("coverage: doctests aren't included in the coverage as that needs a nightly toolchain");
+            }
+        }
+    }
+
+    if cargo_cmd == "test" || cargo_cmd == "bench" {
+        // cargo's "Running <test binary>" lines name the test suites.
+        cmd.stderr(Stdio::piped());
     }
 
     println!("spawning: {:?}", &cmd);
     let mut child = cmd.spawn()?;
+    let (suite_names, stderr_forwarder) = match child.stderr.take().map(forward_suite_names) {
+        Some((names, forwarder)) => (
+            SuiteNames {
+                received: Some(names),
+                ..SuiteNames::default()
+            },
+            Some(forwarder),
+        ),
+        None => (SuiteNames::default(), None),
+    };
     let out_stream = Option::take(&mut child.stdout).unwrap();
     let buf = BufReader::new(out_stream);
     let mut inspection_logged = false;
-    let ctx = Context {
+    let mut ctx = Context {
         debug,
         brand: brand.to_owned(),
         min_threshold,
         cargo_cmd: cargo_cmd.to_owned(),
+        tap: tap.as_ref().map(|_| Tap::default()),
+        suite_names,
+        codeclimate,
+        checkstyle,
+        roots,
+        lint_groups: if cargo_cmd != "clean" && cargo_cmd != "fmt" {
+            LintGroups::detect()
+        } else {
+            LintGroups::default()
+        },
+        drop_external,
+        severities,
+        policy,
+        baseline,
+        ..Context::default()
     };
 
     for line in buf.lines() {
@@ -166,13 +297,16 @@ fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
             if ctx.debug {
                 println!("{}", &line);
             }
-            let stream = Deserializer::from_str(&line);
+            let stream = Deserializer::from_str(line);
             for value in stream.into_iter() {
                 match value {
                     Ok(Value::Object(event)) => {
-                        if let Ok(reported) =
-                            process(&ctx, &event, &mut std::io::stdout(), &mut std::io::stderr())
-                        {
+                        if let Ok(reported) = process(
+                            &mut ctx,
+                            &event,
+                            &mut std::io::stdout(),
+                            &mut std::io::stderr(),
+                        ) {
                             if reported {
                                 inspection_logged = true;
                             }
@@ -190,6 +324,54 @@ fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
             print!("{:?}", line);
         }
     }
+    if let (Some(tap), Some(path)) = (&mut ctx.tap, &tap) {
+        write_report(&brand, path, tap.finish());
+    }
+    if cargo_cmd != "clean" && cargo_cmd != "fmt" {
+        diagnostic_statistics(&ctx, &mut std::io::stdout())?;
+        multi_target_notes(&ctx.diagnostics, &mut std::io::stdout())?;
+    }
+    if let Some(baseline) = &ctx.baseline {
+        for (key, value) in &[
+            ("new", baseline.new),
+            ("existing", baseline.existing),
+            ("fixed", baseline.fixed()),
+        ] {
+            println!(
+                "##{}[buildStatisticValue key='diagnostics.{}' value='{}']",
+                brand, key, value
+            );
+        }
+    }
+    if let Some(path) = &write_baseline {
+        match write_file(path, baseline_json(&ctx.diagnostics)) {
+            Ok(()) => println!(
+                "Wrote {} warnings to baseline {}",
+                ctx.diagnostics
+                    .iter()
+                    .filter(|diag| diag.level == "warning")
+                    .count(),
+                path.display()
+            ),
+            Err(err) => println!(
+                "cargo-service-message: could not write baseline {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    if let Some(path) = &ctx.codeclimate {
+        write_report(&brand, path, code_climate_report(&ctx.diagnostics));
+    }
+    if let Some(path) = &fixes {
+        let patch = fixes_patch(&ctx.diagnostics);
+        if !patch.is_empty() {
+            write_report(&brand, path, patch);
+        }
+    }
+    if let Some(path) = &ctx.checkstyle {
+        write_report(&brand, path, checkstyle_report(&ctx.diagnostics));
+    }
     //TODO only if file exists?
     println!(
         "##{}[publishArtifacts '{}']",
@@ -202,7 +384,12 @@ fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
             .unwrap()
     );
 
-    let result = Ok(child.wait()?).map(|exit_status| {
+    let exit_status = child.wait()?;
+    if let Some(forwarder) = stderr_forwarder {
+        // So that none of cargo's last lines are lost.
+        let _ = forwarder.join();
+    }
+    let result = Ok(exit_status).map(|exit_status| {
         if let Some(exit_code) = exit_status.code() {
             // Tests and Clippy fail the build with non-zero exit codes if there's failures.
             // Better to have it return success and let people have
@@ -217,786 +404,5403 @@ fn run_cargo(args: &[String]) -> Result<i32, Box<dyn Error>> {
         }
     });
 
-    if coverage && !coverage_no_report {
-        gen_coverage_report(&ctx, mode);
+    let result = result.map(|exit_code| {
+        if exit_code == 0 && ctx.policy_failed {
+            println!(
+                "cargo-service-message: failing as diagnostics were found with the 'fail' policy."
+            );
+            1
+        } else {
+            exit_code
+        }
+    });
+
+    if coverage {
+        let mut objects = ctx.executables.clone();
+        objects.extend(doctest_objects(&doctests_dir()));
+        record_coverage_objects(&coverage_data_dir(), &objects)?;
+        let pending = coverage_data_dir().join("pending");
+        if coverage_no_report {
+            std::fs::write(pending, "")?;
+        } else if pending.exists() {
+            std::fs::remove_file(pending)?;
+        }
+    }
+    let (coverage_totals, coverage_passed) = if coverage && !coverage_no_report {
+        report_coverage(&ctx, &coverage_options, mode)?
+    } else {
+        (None, true)
+    };
+    let result = if coverage_passed {
+        result
+    } else {
+        result.map(|exit_code| if exit_code == 0 { 1 } else { exit_code })
+    };
+
+    if let Some(path) = &summary {
+        let version = rustc_version();
+        let toolchain = version.lines().next().unwrap_or_default();
+        let exit_code = *result.as_ref().unwrap_or(&-1);
+        let json = summary_json(&ctx, args, toolchain, exit_code, coverage_totals.as_ref());
+        write_report(&brand, path, serde_json::to_string_pretty(&json)?);
+    }
+    if let Some(path) = &report {
+        for (extension, contents) in &[
+            ("md", markdown_report(&ctx, coverage_totals.as_ref())),
+            ("html", html_report(&ctx, coverage_totals.as_ref())),
+        ] {
+            let mut path = path.clone().into_os_string();
+            path.push(".");
+            path.push(extension);
+            write_report(&brand, Path::new(&path), contents);
+        }
     }
     result
 }
 
-fn gen_coverage_report(ctx: &Context, mode: &str) {
-    let target = target_dir();
-    let mut grcov = grcov_cmd(
-        &target.join(mode),
-        "html",
-        target.join("coverage").to_str().unwrap(),
-    );
-    println!("{:?}", grcov);
-    let grcov_html_out = grcov.output();
+/// Slowest compilation units first.
+fn slowest_units(ctx: &Context) -> Vec<&(String, String, f64)> {
+    let mut units: Vec<_> = ctx.unit_times.iter().collect();
+    units.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+    units.truncate(10);
+    units
+}
 
-    let json_filename: PathBuf = target.join("coverage.json");
-    let mut grcov = grcov_cmd(
-        &target.join(mode),
-        "covdir",
-        json_filename.to_str().unwrap(),
+/// Warnings grouped by lint name.
+fn warnings_by_lint(ctx: &Context) -> BTreeMap<&str, Vec<&Diagnostic>> {
+    let mut by_lint: BTreeMap<&str, Vec<&Diagnostic>> = BTreeMap::new();
+    for diag in ctx
+        .diagnostics
+        .iter()
+        .filter(|diag| diag.level == "warning" && diag.is_new)
+    {
+        by_lint.entry(&diag.code).or_default().push(diag);
+    }
+    by_lint
+}
+
+fn targets_note(diag: &Diagnostic) -> String {
+    if diag.targets > 1 {
+        format!(" ({} targets)", diag.targets)
+    } else {
+        String::new()
+    }
+}
+
+/// A fenced code block with a fence longer than any run of backticks in `text`.
+fn md_code_block(text: &str) -> String {
+    let longest_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{}\n{}\n{}", fence, text, fence)
+}
+
+/// Text that can go in a markdown table cell.
+fn md_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
+
+/// Human readable summary of the run for people outside of TeamCity.
+fn markdown_report(ctx: &Context, coverage: Option<&Coverage>) -> String {
+    let mut md = format!("# cargo {} summary\n", ctx.cargo_cmd);
+
+    if !ctx.failures.is_empty() {
+        md.push_str("\n## Failing tests\n");
+        for (name, stdout) in &ctx.failures {
+            md.push_str(&format!(
+                "\n### {}\n\n{}\n",
+                name,
+                md_code_block(stdout.trim_end())
+            ));
+        }
+    }
+
+    let by_lint = warnings_by_lint(ctx);
+    if !by_lint.is_empty() {
+        md.push_str("\n## Warnings\n");
+        for (lint, diags) in by_lint {
+            md.push_str(&format!("\n### {} ({})\n\n", lint, diags.len()));
+            for diag in diags {
+                md.push_str(&format!(
+                    "* `{}:{}` {}{}\n",
+                    diag.file,
+                    diag.line,
+                    diag.message,
+                    targets_note(diag)
+                ));
+            }
+        }
+    }
+
+    let units = slowest_units(ctx);
+    if !units.is_empty() {
+        md.push_str(
+            "\n## Slowest compilation units\n\n| Unit | Mode | Seconds |\n| --- | --- | ---: |\n",
+        );
+        for (unit, mode, secs) in units {
+            md.push_str(&format!(
+                "| {} | {} | {:.2} |\n",
+                md_cell(unit),
+                md_cell(mode),
+                secs
+            ));
+        }
+    }
+
+    if let Some(coverage) = coverage {
+        md.push_str("\n## Coverage\n\n");
+        for (name, count) in coverage_measures(coverage) {
+            md.push_str(&format!(
+                "{:.2}% of {} covered ({} of {})\n",
+                count.percent(),
+                name,
+                count.covered,
+                count.total
+            ));
+        }
+        for (heading, rows) in [("Crate", &coverage.by_crate), ("File", &coverage.by_file)] {
+            if !rows.is_empty() {
+                md.push_str(&format!(
+                    "\n| {} | Lines covered | Lines | % |\n| --- | ---: | ---: | ---: |\n",
+                    heading
+                ));
+                for (name, lines) in least_covered(rows) {
+                    md.push_str(&format!(
+                        "| {} | {} | {} | {:.2} |\n",
+                        md_cell(name),
+                        lines.covered,
+                        lines.total,
+                        lines.percent()
+                    ));
+                }
+            }
+        }
+    }
+    md
+}
+
+/// The same as `markdown_report` but as a single self contained html page.
+fn html_report(ctx: &Context, coverage: Option<&Coverage>) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>cargo {0} summary</title></head><body>\n<h1>cargo {0} summary</h1>\n",
+        escape_xml(&ctx.cargo_cmd)
     );
-    match grcov.output() {
-        Ok(output) => {
-            if let Ok(coverage) = std::fs::read_to_string(json_filename) {
-                let (percent, lcov, _lmiss, ltot) = parse_cov(&coverage);
 
-                println!(
-                    "##{}[buildStatisticValue key='CodeCoverageL' value='{:.6}']",
-                    ctx.brand, percent
-                );
-                println!(
-                    "##{}[buildStatisticValue key='CodeCoverageAbsLCovered' value='{:.6}']",
-                    ctx.brand, lcov
-                );
-                println!(
-                    "##{}[buildStatisticValue key='CodeCoverageAbsLTotal' value='{:.6}']",
-                    ctx.brand, ltot
-                );
-            } else {
-                println!("Coverage didn't produce json file - output follows:");
-                println!("{}", &String::from_utf8_lossy(&output.stdout));
-                println!("{}", &String::from_utf8_lossy(&output.stderr));
-                println!("-- fin --");
+    if !ctx.failures.is_empty() {
+        html.push_str("<h2>Failing tests</h2>\n");
+        for (name, stdout) in &ctx.failures {
+            html.push_str(&format!(
+                "<h3>{}</h3>\n<pre>{}</pre>\n",
+                escape_xml(name),
+                escape_xml(stdout.trim_end())
+            ));
+        }
+    }
+
+    let by_lint = warnings_by_lint(ctx);
+    if !by_lint.is_empty() {
+        html.push_str("<h2>Warnings</h2>\n");
+        for (lint, diags) in by_lint {
+            html.push_str(&format!(
+                "<h3>{} ({})</h3>\n<ul>\n",
+                escape_xml(lint),
+                diags.len()
+            ));
+            for diag in diags {
+                html.push_str(&format!(
+                    "<li><code>{}:{}</code> {}{}</li>\n",
+                    escape_xml(&diag.file),
+                    diag.line,
+                    escape_xml(&diag.message),
+                    targets_note(diag)
+                ));
             }
+            html.push_str("</ul>\n");
         }
-        Err(err) => println!("coverage failed to execute: {:?}", err),
     }
 
-    if let Err(err) = grcov_html_out {
-        eprintln!("grcov error while processing coverage: {}", err);
-    } else {
-        //An attempt to override the css file...
-        //std::thread::sleep(Duration::new(1, 0));
-        // use std::fs::File;
-        // use std::io::Write;
-        // let file_name = std::env::current_dir()
-        //     .unwrap()
-        //     .join("target/coverage/grcov.css");
-        // println!("going to {:?}", &file_name);
-
-        // if let Err(rr) = std::fs::remove_file(&file_name) {
-        //     eprintln!("Error {:?}", rr);
-        // }
-        // println!("did {:?}", &file_name);
-        // println!("{}", CSS);
-        // {
-        //     let mut f = File::create(file_name).expect("Unable to create file");
-        //     f.write_all(CSS.as_bytes()).expect("Unable to write data");
-        // }
-        // f.drop();
+    let units = slowest_units(ctx);
+    if !units.is_empty() {
+        html.push_str("<h2>Slowest compilation units</h2>\n<table>\n<tr><th>Unit</th><th>Mode</th><th>Seconds</th></tr>\n");
+        for (unit, mode, secs) in units {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{:.2}</td></tr>\n",
+                escape_xml(unit),
+                escape_xml(mode),
+                secs
+            ));
+        }
+        html.push_str("</table>\n");
+    }
 
-        println!(
-            "##{}[publishArtifacts '{}/**=>coverage.zip']",
-            ctx.brand,
-            target.join("coverage").to_str().unwrap()
-        );
+    if let Some(coverage) = coverage {
+        html.push_str("<h2>Coverage</h2>\n");
+        for (name, count) in coverage_measures(coverage) {
+            html.push_str(&format!(
+                "<p>{:.2}% of {} covered ({} of {})</p>\n",
+                count.percent(),
+                name,
+                count.covered,
+                count.total
+            ));
+        }
+        for (heading, rows) in [("Crate", &coverage.by_crate), ("File", &coverage.by_file)] {
+            if !rows.is_empty() {
+                html.push_str(&format!(
+                    "<table>\n<tr><th>{}</th><th>Lines covered</th><th>Lines</th><th>%</th></tr>\n",
+                    heading
+                ));
+                for (name, lines) in least_covered(rows) {
+                    html.push_str(&format!(
+                        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.2}</td></tr>\n",
+                        escape_xml(name),
+                        lines.covered,
+                        lines.total,
+                        lines.percent()
+                    ));
+                }
+                html.push_str("</table>\n");
+            }
+        }
     }
+    html.push_str("</body></html>\n");
+    html
 }
 
-fn target_dir() -> PathBuf {
-    env::var("CARGO_TARGET_DIR")
-        .map(|s| PathBuf::from_str(&s).unwrap())
-        .unwrap_or_else(|_| std::env::current_dir().unwrap().join("target"))
+/// Files or crates ordered from least to most covered, skipping any without lines.
+fn least_covered(rows: &[(String, CoverageCount)]) -> Vec<&(String, CoverageCount)> {
+    let mut rows: Vec<_> = rows.iter().filter(|(_, lines)| lines.total > 0).collect();
+    rows.sort_by(|a, b| {
+        a.1.percent()
+            .partial_cmp(&b.1.percent())
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    rows
 }
 
-/// Command to generate coverage
-fn grcov_cmd(input_dir: &Path, output_type: &str, output_dir: &str) -> Command {
-    let mut grcov = Command::new("grcov");
-    grcov
-        .arg(input_dir.to_str().unwrap())
-        .arg("-s")
-        .arg(".")
-        .arg("-t")
-        .arg(output_type)
-        .arg("--llvm")
-        .arg("--branch")
-        .arg("--ignore-not-existing")
-        .arg("-o")
-        .arg(output_dir);
+/// The measures of coverage that have anything to count (lines always).
+fn coverage_measures(coverage: &Coverage) -> Vec<(&'static str, CoverageCount)> {
+    vec![
+        ("lines", coverage.lines),
+        ("functions", coverage.functions),
+        ("branches", coverage.branches),
+        ("files", coverage.files),
+        ("changed lines", coverage.changed_lines),
+    ]
+    .into_iter()
+    .filter(|(name, count)| *name == "lines" || count.total > 0)
+    .collect()
+}
 
-    // Ignore 3rd party crates
-    if let Ok(cargo_home) = cargo_home() {
-        grcov.arg("--ignore").arg(format!("{}/**", cargo_home));
+/// Version of the `--summary` json layout. Bump on any breaking change.
+const SUMMARY_VERSION: u32 = 1;
+
+/// The machine readable summary of the whole run.
+fn summary_json(
+    ctx: &Context,
+    args: &[String],
+    toolchain: &str,
+    exit_code: i32,
+    coverage: Option<&Coverage>,
+) -> Value {
+    let mut slowest: Vec<&(String, f64)> = ctx.test_times.iter().collect();
+    slowest.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    slowest.truncate(10);
+
+    let mut by_lint: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut by_crate: BTreeMap<&str, BTreeMap<&str, u64>> = BTreeMap::new();
+    for diag in &ctx.diagnostics {
+        *by_lint.entry(&diag.code).or_default() += 1;
+        *by_crate
+            .entry(&diag.package)
+            .or_default()
+            .entry(&diag.level)
+            .or_default() += 1;
     }
-    grcov
+
+    serde_json::json!({
+        "version": SUMMARY_VERSION,
+        "command": args,
+        "toolchain": toolchain,
+        "exit_code": exit_code,
+        "suites": ctx.suites,
+        "slowest_tests": slowest
+            .iter()
+            .map(|(name, secs)| serde_json::json!({ "name": name, "seconds": secs }))
+            .collect::<Vec<_>>(),
+        "diagnostics": {
+            "total": ctx.diagnostics.len(),
+            "by_lint": by_lint,
+            "by_crate": by_crate,
+        },
+        "compile_times": ctx
+            .unit_times
+            .iter()
+            .map(|(unit, mode, secs)| serde_json::json!({ "unit": unit, "mode": mode, "seconds": secs }))
+            .collect::<Vec<_>>(),
+        "coverage": coverage.map(coverage_json),
+    })
 }
 
-//static CSS: &str = include_str!("grcov.css");
+/// The coverage totals as they appear in the `--summary`.
+fn coverage_json(coverage: &Coverage) -> Value {
+    serde_json::json!({
+            "percent": coverage.lines.percent(),
+            "lines_covered": coverage.lines.covered,
+            "lines_missed": coverage.lines.missed(),
+            "lines_total": coverage.lines.total,
+            "functions_covered": coverage.functions.covered,
+            "functions_total": coverage.functions.total,
+            "branches_covered": coverage.branches.covered,
+            "branches_total": coverage.branches.total,
+            "files_covered": coverage.files.covered,
+            "files_total": coverage.files.total,
+            "changed_lines_covered": coverage.changed_lines.covered,
+            "changed_lines_total": coverage.changed_lines.total,
+            "by_crate": coverage
+                .by_crate
+                .iter()
+                .map(|(name, lines)| serde_json::json!({
+                    "name": name,
+                    "lines_covered": lines.covered,
+                    "lines_total": lines.total,
+                }))
+                .collect::<Vec<_>>(),
+    })
+}
 
-struct Context {
-    debug: bool,
-    brand: String,
-    min_threshold: f64,
-    cargo_cmd: String,
+/// How coverage reports are made from the `-Cinstrument-coverage` profiles.
+#[derive(Clone, Copy, PartialEq)]
+enum CoverageEngine {
+    /// `llvm-profdata` and `llvm-cov` from the `llvm-tools-preview` rustup component.
+    LlvmCov,
+    Grcov,
 }
 
-/// Processes a line of output from cargo and potentially augments that output with service messages.
-/// Returns true if inspection was rasied.
-fn process(
-    ctx: &Context,
-    event: &Map<String, Value>,
-    out: &mut dyn Write,
-    err: &mut dyn Write,
-) -> Result<bool, Box<dyn Error>> {
-    let brand = &ctx.brand;
-    let mut inspection_logged = false;
-    if let Some(Value::String(compiler_msg)) = event.get("reason") {
-        match compiler_msg.as_ref() {
-            "timing-info" => {
-                parse_timing_info(ctx, event, out, err)?;
-            }
-            "build-script-executed" => {
-                if let Some(Value::String(package_id)) = event.get("package_id") {
-                    // Shame build scripts that run:
-                    writeln!(
-                        out,
-                        "Running build script for {}",
-                        tidy_package_id(package_id)
-                    )?;
-                }
-            }
-            "compiler-artifact" => {
-                let fresh = if let Some(Value::Bool(fresh)) = event.get("fresh") {
-                    *fresh
+impl CoverageEngine {
+    /// Checks the tools needed are installed.
+    fn check(self) -> Result<(), &'static str> {
+        match self {
+            CoverageEngine::LlvmCov => {
+                if llvm_tool("llvm-profdata").is_some() && llvm_tool("llvm-cov").is_some() {
+                    Ok(())
                 } else {
-                    false
-                };
-                if let Some(Value::String(package_id)) = event.get("package_id") {
-                    // Shame build scripts that run:
-                    writeln!(
-                        out,
-                        "Compiling {} {}",
-                        tidy_package_id(package_id),
-                        if fresh { "[fresh]" } else { "" }
-                    )?;
+                    Err("llvm-profdata / llvm-cov not found (rustup component add llvm-tools-preview?)")
                 }
             }
-            "compiler-message" => {
-                if let Some(Value::Object(msg)) = event.get("message") {
-                    if let Ok(true) = parse_compiler_message(ctx, msg, out, err) {
-                        inspection_logged = true;
-                    }
+            CoverageEngine::Grcov => {
+                if Command::new("grcov").arg("--version").output().is_ok() {
+                    Ok(())
+                } else {
+                    Err("grcov not found on path (cargo install grcov?)")
                 }
             }
-            "build-finished" => {}
-            _ => {
-                writeln!(out, "{}", compiler_msg)?;
-                writeln!(out, "{:?}", event)?;
-            }
         }
-    } else if let Some(Value::String(ttype)) = event.get("type") {
-        match ttype.as_ref() {
-            "suite" => match event.get("event") {
-                Some(Value::String(event_name)) => match event_name.as_ref() {
-                    "started" => {
-                        writeln!(out, "##{}[testSuiteStarted name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
-                    }
-                    "ok" => {
-                        writeln!(out, "##{}[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
-                    }
-                    "failed" => {
-                        inspection_logged = true;
-                        writeln!(out, "##{}[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
-                    }
-                    _ => {
-                        writeln!(out, "format unknown {:?}", event)?;
-                    }
-                },
-                _ => {
-                    writeln!(out, "format {:?}", event)?;
-                }
-            },
-            "bench" => {
-                let name = parse_name(&event);
+    }
+}
 
-                if let Some(Value::Number(median)) = event.get("median") {
-                    writeln!(
-                        out,
-                        "##{}[buildStatisticValue key='bench.{}.median' value='{:.6}']",
-                        brand,
-                        name,
-                        median.as_f64().unwrap()
-                    )?;
-                }
-                if let Some(Value::Number(devation)) = event.get("deviation") {
-                    writeln!(
-                        out,
-                        "##{}[buildStatisticValue key='bench.{}.deviation' value='{:.6}']",
-                        brand, name, devation
-                    )?;
-                }
-            }
-            "test" => match event.get("event") {
-                Some(Value::String(s)) => {
-                    return parse_test_event(ctx, s, event, out, err);
-                }
-                _ => {
-                    writeln!(out, "unhandled event - please report: {:?}", event)?;
-                }
-            },
-            _ => {
-                writeln!(out, "unhandled event - please report: {:?}", event)?;
-            }
+/// Where the raw profiles and the list of instrumented binaries are kept.
+fn coverage_data_dir() -> PathBuf {
+    target_dir().join("coverage-data")
+}
+
+/// `rustc -vV`: e.g. `rustc 1.80.0 (051478957 2024-07-21)` followed by
+/// `key: value` lines such as `host: x86_64-unknown-linux-gnu`.
+fn rustc_version() -> String {
+    Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default()
+}
+
+/// `#[coverage(off)]` is still unstable.
+fn is_nightly(version: &str) -> bool {
+    version.contains("nightly") || version.contains("-dev")
+}
+
+/// `--check-cfg` is stable from rust 1.80.
+fn has_check_cfg(version: &str) -> bool {
+    let minor = version
+        .split_whitespace()
+        .nth(1)
+        .and_then(|number| number.split('.').nth(1))
+        .and_then(|minor| minor.parse::<u32>().ok());
+    matches!(minor, Some(minor) if minor >= 80)
+}
+
+/// Where instrumented builds go.
+fn coverage_target_dir() -> PathBuf {
+    target_dir().join("coverage-build")
+}
+
+/// How long a coverage session lasts outside of CI (where it's tied to the build instead).
+const COVERAGE_SESSION_HOURS: u64 = 12;
+
+/// The `session` marker left by `coverage start`.
+#[derive(Debug, PartialEq)]
+struct CoverageSession {
+    /// The CI build that started it.
+    build: Option<String>,
+    /// Seconds since the unix epoch.
+    started: u64,
+}
+
+impl CoverageSession {
+    fn parse(marker: &str) -> CoverageSession {
+        let field = |name: &str| {
+            marker
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+        };
+        CoverageSession {
+            build: field("build").map(str::to_string),
+            started: field("started")
+                .and_then(|started| started.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+
+    /// A session belongs to the build that started it (or, outside of CI, expires)
+    /// so that one that was never reported doesn't instrument everything after it.
+    fn is_current(&self, build: Option<&str>, now: u64) -> bool {
+        match (&self.build, build) {
+            (Some(started_by), Some(build)) => started_by == build,
+            _ => now.saturating_sub(self.started) < COVERAGE_SESSION_HOURS * 60 * 60,
         }
     }
-    Ok(inspection_logged)
 }
 
-fn parse_compiler_message(
-    ctx: &Context,
-    msg: &Map<String, Value>,
-    out: &mut dyn Write,
-    err: &mut dyn Write,
-) -> Result<bool, Box<dyn Error>> {
-    if let Some(Value::String(level)) = msg.get("level") {
-        let level = if level.as_str() == "error: internal compiler error" {
-            "error".to_string()
-        } else {
-            level.to_string()
-        };
-        if level.as_str() == "warning" || level.as_str() == "error" {
-            let message = if let Some(Value::String(message)) = msg.get("rendered") {
-                message
-            } else {
-                ""
-            };
+impl std::fmt::Display for CoverageSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(build) = &self.build {
+            writeln!(f, "build={}", build)?;
+        }
+        writeln!(f, "started={}", self.started)
+    }
+}
 
-            //TODO ask jetbrains if there's a way we can embed html here as message could
-            // do with being monospaced.
-            // let message = "<pre>".to_string() + &message + "</pre>";
+/// Identifies the CI build being run (TeamCity and most other CI servers set BUILD_NUMBER).
+fn build_id() -> Option<String> {
+    let number = env::var("BUILD_NUMBER").ok()?;
+    let config = ["TEAMCITY_PROJECT_NAME", "TEAMCITY_BUILDCONF_NAME"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some(format!("{}#{}", config, number))
+}
 
-            let (code, explanation) = if let Some(Value::Object(code)) = msg.get("code") {
-                let explanation = if let Some(Value::String(explanation)) = code.get("explanation")
-                {
-                    explanation
-                } else {
-                    "no explanation"
-                };
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
 
-                if let Some(Value::String(code)) = code.get("code") {
-                    (code.as_ref(), explanation)
-                } else {
-                    ("other", explanation)
-                }
-            } else {
-                ("other", "no explanation")
-            };
+/// Whether `coverage start` has begun a session that's still going. A stale session
+/// (its `coverage report` never ran) is cleared away along with its profiles.
+fn active_coverage_session(dir: &Path) -> bool {
+    let marker = match std::fs::read_to_string(dir.join("session")) {
+        Ok(marker) => marker,
+        Err(_) => return false,
+    };
+    let session = CoverageSession::parse(&marker);
+    if session.is_current(build_id().as_deref(), unix_time()) {
+        return true;
+    }
+    println!(
+        "coverage: clearing out the coverage session started by {} as it was never reported",
+        session.build.as_deref().unwrap_or("an earlier run")
+    );
+    for marker in &["pending", "session"] {
+        let _ = std::fs::remove_file(dir.join(marker));
+    }
+    if let Err(err) = start_coverage_run(dir) {
+        println!("coverage: could not clear out {}: {}", dir.display(), err);
+    }
+    false
+}
 
-            let mut file = "no_file";
-            let mut line = 0u64;
-            if let Some(Value::Array(spans)) = msg.get("spans") {
-                if !spans.is_empty() {
-                    if let Value::Object(span) = &spans[0] {
-                        if let Some(Value::String(file_name)) = span.get("file_name") {
-                            file = &file_name;
-                        }
-                        if let Some(Value::Number(line_number)) = span.get("line_start") {
-                            line = line_number.as_u64().unwrap_or(0);
-                        }
-                    }
-                }
+/// Clears out the profiles of previous runs, unless the last run
+/// was `--cover-without-report` (or is part of a coverage session) in which case this run adds to it.
+fn start_coverage_run(dir: &Path) -> std::io::Result<()> {
+    if dir.join("pending").exists() || dir.join("session").exists() {
+        println!("adding to the coverage of previous --cover-without-report runs");
+        return Ok(());
+    }
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() {
+                std::fs::remove_file(path)?;
             }
+        }
+    }
+    let doctests = dir.join("doctests");
+    if doctests.exists() {
+        std::fs::remove_dir_all(doctests)?;
+    }
+    Ok(())
+}
 
-            if level == "error" {
-                writeln!(
-                    out,
-                    "##{}[buildProblem description='{}' identity='{}']",
-                    ctx.brand,
-                    escape_message(message),
-                    code
-                )?;
-                writeln!(err, "{}", message)?;
-            } else {
-                if !message.contains("1 warning emitted") && !message.contains(" warnings emitted")
-                {
-                    writeln!(
-                        out,
-                        "##{}[inspectionType id='{}' category='{}' name='{}' description='{}']",
-                        ctx.brand, code, level, code, explanation
-                    )?;
-                    writeln!(out,
-                        "##{}[inspection typeId='{}' message='{}' file='{}' line='{}' SEVERITY='{}']",
-                        ctx.brand,
-                        code,
-                        escape_message(message),
-                        file,
-                        line,
-                        level
-                    )?;
-                }
-                writeln!(out, "{}", message)?;
-            }
+/// Where `--persist-doctests` keeps the compiled doctests.
+fn doctests_dir() -> PathBuf {
+    coverage_data_dir().join("doctests")
+}
 
-            //additional attribute='<additional attribute>'
-            Ok(true)
-        // } else {
-        //     println!("unhandled msg: {:?}", msg);
-        //     Ok(false)
-        // }
-        } else {
-            writeln!(out, "unhandled message: {:?}", msg)?;
-            Ok(false)
+/// The persisted doctest binaries (a `rust_out` in a directory per doctest).
+fn doctest_objects(dir: &Path) -> Vec<String> {
+    let mut objects = vec![];
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if path.is_dir() {
+                objects.extend(doctest_objects(&path));
+            } else if path.file_stem() == Some("rust_out".as_ref()) {
+                objects.push(path.to_string_lossy().into_owned());
+            }
         }
-    } else {
-        writeln!(out, "{:?}", msg)?;
-        Ok(false)
     }
+    objects.sort();
+    objects
 }
 
-fn parse_test_event(
-    ctx: &Context,
-    event_type: &str,
-    event: &Map<String, Value>,
-    out: &mut dyn Write,
-    _err: &mut dyn Write,
-) -> Result<bool, Box<dyn Error>> {
-    //TODO split parsing from output!
-    let name = parse_name(&event);
+/// Remembers the binaries that were built so that `llvm-cov` can map their profiles back to source.
+/// Binaries that have since been deleted (e.g. by `cargo clean`) are forgotten.
+fn record_coverage_objects(dir: &Path, executables: &[String]) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let list = dir.join("objects.txt");
+    let mut objects: Vec<String> = std::fs::read_to_string(&list)
+        .unwrap_or_default()
+        .lines()
+        .filter(|object| Path::new(object).exists())
+        .map(str::to_string)
+        .collect();
+    for executable in executables {
+        if !objects.contains(executable) {
+            objects.push(executable.clone());
+        }
+    }
+    std::fs::write(list, objects.join("\n"))
+}
 
-    match event_type {
-        "started" => {
-            writeln!(
-                out,
-                "##{}[flowStarted flowId='{}' parent='test_suite_flow_id']",
-                ctx.brand, name
-            )?;
-            writeln!(out, "##{}[testStarted flowId='{}' name='{}' captureStandardOutput='true' parent='test_suite_flow_id']", ctx.brand, name, name)?;
-            Ok(false)
+/// Finds an llvm tool in the toolchain's `llvm-tools-preview` component, or else on the path.
+fn llvm_tool(name: &str) -> Option<PathBuf> {
+    let exe = format!("{}{}", name, env::consts::EXE_SUFFIX);
+    let sysroot = Command::new("rustc")
+        .arg("--print=sysroot")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned());
+    if let Ok(sysroot) = sysroot {
+        if let Some(host) = rustc_version()
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+        {
+            let path = Path::new(sysroot.trim())
+                .join("lib/rustlib")
+                .join(host.trim())
+                .join("bin")
+                .join(&exe);
+            if path.exists() {
+                return Some(path);
+            }
         }
-        "ok" => {
-            if let Some(Value::String(exec_time)) = event.get("exec_time") {
-                writeln!(
-                    out,
-                    "##{}[testFinished flowId='{}' name='{}' duration='{}']",
-                    ctx.brand, name, name, exec_time
-                )?;
-            } else {
-                writeln!(
-                    out,
-                    "##{}[testFinished flowId='{}' name='{}']",
-                    ctx.brand, name, name
-                )?;
+    }
+    Command::new(&exe)
+        .arg("--version")
+        .output()
+        .ok()
+        .map(|_| PathBuf::from(exe))
+}
+
+/// `cargo service-message coverage start`, `coverage run -- <program> [args]` or `coverage report`:
+/// a coverage session that collects the profiles of any number of cargo commands
+/// and other programs into one report.
+fn run_coverage_command(args: &[String]) -> Result<i32, Box<dyn Error>> {
+    let params = std::env::var("SERVICE_MESSAGE").unwrap_or_else(|_| "".into());
+    let brand = std::env::var("SERVICE_BRAND").unwrap_or_else(|_| "teamcity".to_owned());
+    let data = coverage_data_dir();
+    let profile_file = data.join("%p-%m.profraw");
+    match args.first().map(String::as_str) {
+        Some("start") => {
+            let started = std::fs::create_dir_all(&data)
+                .and_then(|_| {
+                    for marker in &["pending", "session"] {
+                        let _ = std::fs::remove_file(data.join(marker));
+                    }
+                    start_coverage_run(&data)
+                })
+                .and_then(|_| {
+                    let session = CoverageSession {
+                        build: build_id(),
+                        started: unix_time(),
+                    };
+                    std::fs::write(data.join("session"), session.to_string())
+                });
+            if let Err(err) = started {
+                return Err(
+                    format!("could not start coverage in {}: {}", data.display(), err).into(),
+                );
             }
-            writeln!(out, "##{}[flowFinished flowId='{}']", ctx.brand, name)?;
-            Ok(false)
+            // So that instrumented programs run by later build steps write their profiles here too.
+            println!(
+                "##{}[setParameter name='env.LLVM_PROFILE_FILE' value='{}']",
+                brand,
+                escape_message(&profile_file.to_string_lossy())
+            );
+            println!(
+                "coverage session started: cargo service-message test/run/build will now be instrumented until 'coverage report'"
+            );
+            Ok(0)
         }
-        "ignored" => {
-            // Bench ignores all the other tests. So if someone's running bench and test
-            // we don't want to return the results of the tests and say they're ignored
-            // - it's jolly confusing!
-            if ctx.cargo_cmd != "bench" {
-                writeln!(
-                    out,
-                    "##{}[testIgnored name='{}' message='ignore reason not yet available.']",
-                    ctx.brand, name
-                )?;
+        Some("run") => {
+            let mut command = args[1..].iter().skip_while(|arg| *arg == "--");
+            let program = command
+                .next()
+                .ok_or("expected 'coverage run -- <program> [args]'")?;
+            let status = Command::new(program)
+                .args(command)
+                .env("LLVM_PROFILE_FILE", &profile_file)
+                .status()
+                .map_err(|err| format!("could not run {}: {}", program, err))?;
+            match find_program(program) {
+                Some(path) => {
+                    record_coverage_objects(&data, &[path.to_string_lossy().into_owned()])
+                        .map_err(|err| format!("could not write to {}: {}", data.display(), err))?
+                }
+                None => println!(
+                    "coverage: {} not found so its coverage may not be mapped back to source",
+                    program
+                ),
             }
-            Ok(false)
+            Ok(status.code().unwrap_or(-1))
         }
-        "failed" => {
-            let stdout = if let Some(Value::String(stdout)) = event.get("stdout") {
-                stdout
+        Some("report") => {
+            let roots = SourceRoots::detect();
+            let options = CoverageOptions::parse(&params, &roots.workspace);
+            if let Err(missing) = options.engine.check() {
+                println!("cargo-service-message: {} so no coverage.", missing);
+                return Ok(0);
+            }
+            let mode = if contains("--release", args) || param(&params, "--release").is_some() {
+                "release"
             } else {
-                ""
+                "debug"
             };
-            if let Some((left, right)) = find_comparison(stdout) {
-                writeln!(out, "##{}[testFailed type='comparisonFailure' name='{}' flowId='{}' message='test failed' details='{}' expected='{}' actual='{}']", ctx.brand, name, name, escape_message(stdout),
-                escape_message(left),escape_message(right))?;
-            } else {
-                writeln!(
-                    out,
-                    "##{}[testFailed name='{}' flowId='{}' message='test failed' details='{}']",
-                    ctx.brand,
-                    name,
-                    name,
-                    escape_message(stdout)
-                )?;
+            let ctx = Context {
+                brand,
+                cargo_cmd: "coverage".to_owned(),
+                roots,
+                ..Context::default()
+            };
+            let (_, passed) = report_coverage(&ctx, &options, mode)?;
+            for marker in &["pending", "session"] {
+                let _ = std::fs::remove_file(data.join(marker));
             }
-
-            writeln!(
-                out,
-                "##{}[testFinished flowId='{}' name='{}']",
-                ctx.brand, name, name
-            )?;
-            writeln!(out, "##{}[flowFinished flowId='{}']", ctx.brand, name)?;
-            Ok(true)
+            Ok(if passed { 0 } else { 1 })
         }
         _ => {
-            writeln!(out, "failed to parse {:?}", event)?;
-            Ok(false)
+            eprintln!(
+                "expected 'coverage start', 'coverage run -- <program> [args]' or 'coverage report' but got {:?}",
+                args
+            );
+            Ok(1)
         }
     }
 }
 
-fn parse_timing_info(
-    ctx: &Context,
-    event: &Map<String, Value>,
-    out: &mut dyn Write,
-    _err: &mut dyn Write,
-) -> Result<(), Box<dyn Error>> {
-    let name = if let Some(Value::Object(target)) = event.get("target") {
-        if let Some(Value::String(target_name)) = target.get("name") {
-            target_name
-        } else {
-            "anon"
-        }
+/// The path to `program`, looking it up on the PATH if it's just a name (as `Command` does).
+fn find_program(program: &str) -> Option<PathBuf> {
+    if Path::new(program).components().count() > 1 {
+        return std::fs::canonicalize(program).ok();
+    }
+    let names = if cfg!(windows) && Path::new(program).extension().is_none() {
+        vec![program.to_string(), format!("{}.exe", program)]
     } else {
-        "anon"
+        vec![program.to_string()]
     };
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+        .and_then(|found| std::fs::canonicalize(found).ok())
+}
 
-    let compile_mode = if let Some(Value::String(compile_mode)) = event.get("mode") {
-        compile_mode
-    } else {
-        "mode"
+/// The SERVICE_MESSAGE settings for how coverage is reported.
+struct CoverageOptions {
+    engine: CoverageEngine,
+    filter: CoverageFilter,
+    gate: CoverageGate,
+    /// Git ref to work out the coverage of changed lines against.
+    diff_base: Option<String>,
+}
+
+impl CoverageOptions {
+    /// The default `--cover-baseline` goes in the `workspace` root.
+    fn parse(params: &str, workspace: &Path) -> CoverageOptions {
+        CoverageOptions {
+            engine: if param(params, "--cover-engine") == Some("grcov") {
+                CoverageEngine::Grcov
+            } else {
+                CoverageEngine::LlvmCov
+            },
+            filter: CoverageFilter::parse(params),
+            gate: CoverageGate {
+                minimums: parse_coverage_minimums(param(params, "--cover-min").unwrap_or("")),
+                max_drop: param(params, "--cover-max-drop")
+                    .and_then(|drop| drop.parse().ok())
+                    .unwrap_or(0.),
+                diff_minimum: param(params, "--cover-diff-min").and_then(|min| min.parse().ok()),
+                baseline: param(params, "--cover-baseline").map(|path| {
+                    if path.is_empty() {
+                        workspace.join("service-message-coverage.json")
+                    } else {
+                        PathBuf::from(path)
+                    }
+                }),
+            },
+            diff_base: param(params, "--cover-diff").map(|base| {
+                if base.is_empty() {
+                    "origin/HEAD".to_string()
+                } else {
+                    base.to_string()
+                }
+            }),
+        }
+    }
+}
+
+/// Generates the coverage report and checks it against the `CoverageGate`.
+/// Returns the totals and false if coverage is too low.
+fn report_coverage(
+    ctx: &Context,
+    options: &CoverageOptions,
+    mode: &str,
+) -> std::io::Result<(Option<Coverage>, bool)> {
+    let totals = gen_coverage_report(ctx, options, mode);
+    let passed = match &totals {
+        Some(totals) => options.gate.check(&ctx.brand, totals)?,
+        None if options.gate.is_configured() => {
+            println!(
+                "##{}[buildProblem description='no coverage report was generated to check coverage against' identity='coverage.missing']",
+                ctx.brand
+            );
+            false
+        }
+        None => true,
     };
+    if !passed {
+        println!("cargo-service-message: failing as coverage is too low.");
+    }
+    Ok((totals, passed))
+}
 
-    if let Some(Value::Number(duration)) = event.get("duration") {
-        if let Some(duration) = duration.as_f64() {
-            if duration > ctx.min_threshold {
-                writeln!(
-                    out,
-                    "##{}[buildStatisticValue key='{} {}' value='{:.6}']",
-                    ctx.brand, compile_mode, name, duration
-                )?;
+/// Generates the coverage report returning the totals.
+fn gen_coverage_report(ctx: &Context, options: &CoverageOptions, mode: &str) -> Option<Coverage> {
+    let report_dir = target_dir().join("coverage");
+    let totals = match options.engine {
+        CoverageEngine::LlvmCov => llvm_cov_report(&report_dir, &options.filter),
+        CoverageEngine::Grcov => grcov_report(&report_dir, mode, &options.filter),
+    };
+    let diff_base = options.diff_base.as_deref();
 
-                writeln!(out, "Compiled {} in {:.2}s", name, duration)?;
+    let mut totals = totals.map(|coverage| coverage_breakdown(coverage, &ctx.roots));
+    let lcov_path = report_dir.join("lcov.info");
+    let hits = lcov_line_hits(
+        &std::fs::read_to_string(&lcov_path).unwrap_or_default(),
+        &std::env::current_dir().unwrap(),
+        &ctx.roots,
+    );
+    if let (Some(coverage), Some(base)) = (&mut totals, diff_base) {
+        match changed_lines(base) {
+            Ok(changed) => {
+                let (count, uncovered) = diff_coverage(&changed, &hits);
+                coverage.changed_lines = count;
+                print!("{}", uncovered_inspections(&ctx.brand, &uncovered));
             }
+            Err(reason) => println!("coverage: no diff coverage as {}", reason),
         }
     }
-    Ok(())
+    if let Some(coverage) = &totals {
+        print!("{}", coverage_statistics(&ctx.brand, coverage));
+        let files = least_covered(&coverage.by_file);
+        if !files.is_empty() {
+            println!("Least covered files:");
+            for (file, lines) in files.iter().take(10) {
+                println!(
+                    "  {:6.2}% {} ({} of {} lines)",
+                    lines.percent(),
+                    file,
+                    lines.covered,
+                    lines.total
+                );
+            }
+        }
+    }
+    if let Some(coverage) = &totals {
+        let cobertura_path = report_dir.join("cobertura.xml");
+        let summary_path = report_dir.join("summary.json");
+        let mut summary = coverage_json(coverage);
+        summary["by_file"] = coverage
+            .by_file
+            .iter()
+            .map(|(name, lines)| {
+                serde_json::json!({
+                    "name": name,
+                    "lines_covered": lines.covered,
+                    "lines_total": lines.total,
+                })
+            })
+            .collect();
+        let written = std::fs::create_dir_all(&report_dir)
+            .and_then(|_| {
+                let branches = lcov_branch_hits(
+                    &std::fs::read_to_string(&lcov_path).unwrap_or_default(),
+                    &std::env::current_dir().unwrap(),
+                    &ctx.roots,
+                );
+                std::fs::write(
+                    &cobertura_path,
+                    cobertura_xml(&hits, &branches, &ctx.roots, unix_time()),
+                )
+            })
+            .and_then(|_| {
+                std::fs::write(
+                    &summary_path,
+                    serde_json::to_string_pretty(&summary).unwrap_or_default(),
+                )
+            });
+        if let Err(err) = written {
+            println!("coverage: couldn't write reports: {}", err);
+        }
+        for path in &[lcov_path, cobertura_path, summary_path] {
+            if path.exists() {
+                println!("##{}[publishArtifacts '{}']", ctx.brand, path.display());
+            }
+        }
+    }
+    if report_dir.exists() {
+        println!(
+            "##{}[publishArtifacts '{}/**=>coverage.zip']",
+            ctx.brand,
+            report_dir.to_str().unwrap()
+        );
+    }
+    totals
 }
-fn parse_name(event: &Map<String, Value>) -> String {
-    let name = if let Some(Value::String(name)) = event.get("name") {
-        name
-    } else {
-        "no_name"
+
+/// TeamCity's coverage statistics. Rust has no classes so files stand in for them.
+/// Function and branch keys are left out when there's nothing to count
+/// (branch coverage needs `-Zcoverage-options=branch`).
+fn coverage_statistics(brand: &str, coverage: &Coverage) -> String {
+    let mut stats = String::new();
+    let mut stat = |key: &str, value: f64| {
+        stats.push_str(&format!(
+            "##{}[buildStatisticValue key='{}' value='{:.6}']\n",
+            brand, key, value
+        ));
     };
-    name.replace("::", ".")
+    let lines = &coverage.lines;
+    stat("CodeCoverageL", lines.percent());
+    stat("CodeCoverageAbsLCovered", lines.covered as f64);
+    stat("CodeCoverageAbsLTotal", lines.total as f64);
+    stat("CodeCoverageAbsLMissed", lines.missed() as f64);
+    for (name, lines) in &coverage.by_crate {
+        stat(&format!("CodeCoverageL.crate.{}", name), lines.percent());
+        stat(
+            &format!("CodeCoverageAbsLCovered.crate.{}", name),
+            lines.covered as f64,
+        );
+        stat(
+            &format!("CodeCoverageAbsLTotal.crate.{}", name),
+            lines.total as f64,
+        );
+    }
+    if coverage.changed_lines.total > 0 {
+        let changed = &coverage.changed_lines;
+        stat("CodeCoverageDiffL", changed.percent());
+        stat("CodeCoverageDiffAbsLCovered", changed.covered as f64);
+        stat("CodeCoverageDiffAbsLTotal", changed.total as f64);
+    }
+    for (letter, count) in [
+        ("M", &coverage.functions),
+        ("B", &coverage.branches),
+        ("C", &coverage.files),
+    ] {
+        if count.total > 0 {
+            stat(&format!("CodeCoverage{}", letter), count.percent());
+            stat(
+                &format!("CodeCoverageAbs{}Covered", letter),
+                count.covered as f64,
+            );
+            stat(
+                &format!("CodeCoverageAbs{}Total", letter),
+                count.total as f64,
+            );
+        }
+    }
+    stats
 }
 
-fn escape_message(unescaped: &str) -> String {
-    //TODO:\uNNNN (unicode symbol with code 0xNNNN)  as |0xNNNN
-    unescaped
-        .replace("|", "||")
-        .replace("[", "|[")
-        .replace("]", "|]")
-        .replace("\n", "|n")
-        .replace("\r", "|r")
-        .replace("'", "|'")
+/// Makes the per file paths relative to the checkout and totals them up by workspace crate.
+fn coverage_breakdown(mut coverage: Coverage, roots: &SourceRoots) -> Coverage {
+    let mut by_crate: BTreeMap<String, CoverageCount> = BTreeMap::new();
+    for (file, lines) in &mut coverage.by_file {
+        let path = roots.resolve(file);
+        if let Some(package) = roots.package_of(&path) {
+            let total = by_crate.entry(package.to_string()).or_default();
+            total.covered += lines.covered;
+            total.total += lines.total;
+        }
+        *file = roots.normalise(file).0;
+    }
+    coverage.by_crate = by_crate.into_iter().collect();
+    coverage
 }
 
-fn tidy_package_id(package_id: &str) -> String {
-    package_id
-        .replace(
-            "(registry+https://github.com/rust-lang/crates.io-index)",
-            "",
-        )
-        .replace(
-            "(registry+https://github.com/rust-lang/crates.io-index.git)",
-            "",
+/// Lines added or changed (by file relative to the checkout) since the merge base with `base`.
+fn changed_lines(base: &str) -> Result<BTreeMap<String, BTreeSet<u64>>, String> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    let merge_base = git(&["merge-base", "HEAD", base]).ok_or_else(|| {
+        format!(
+            "there's no merge base with {} (is it fetched? use --cover-diff=<ref> for another base)",
+            base
         )
+    })?;
+    // Fix the prefixes and quoting that parse_diff_lines relies on, whatever the git config.
+    let diff = git(&[
+        "-c",
+        "core.quotePath=false",
+        "diff",
+        "--unified=0",
+        "--no-color",
+        "--no-ext-diff",
+        "--src-prefix=a/",
+        "--dst-prefix=b/",
+        merge_base.trim(),
+    ])
+    .ok_or_else(|| format!("git diff against {} failed", merge_base.trim()))?;
+    Ok(parse_diff_lines(&diff))
 }
 
-fn contains(needle: &str, args: &[String]) -> bool {
-    args.iter().any(|x| x == needle)
+/// The new side's line numbers from each hunk of a `git diff --unified=0`.
+fn parse_diff_lines(diff: &str) -> BTreeMap<String, BTreeSet<u64>> {
+    let mut changed: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    let mut file = None;
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            // (git ends names with spaces in them with a tab.)
+            file = path
+                .trim_end_matches('\t')
+                .strip_prefix("b/")
+                .map(str::to_string);
+        } else if let (Some(file), Some(hunk)) = (&file, line.strip_prefix("@@ ")) {
+            let new = hunk
+                .split_whitespace()
+                .find_map(|range| range.strip_prefix('+'))
+                .unwrap_or("");
+            let (start, count) = new.split_once(',').unwrap_or((new, "1"));
+            if let (Ok(start), Ok(count)) = (start.parse::<u64>(), count.parse::<u64>()) {
+                changed
+                    .entry(file.clone())
+                    .or_default()
+                    .extend(start..start + count);
+            }
+        }
+    }
+    changed
 }
 
-fn find_comparison(msg: &str) -> Option<(&str, &str)> {
-    if let (Some(left_index), Some(right_index)) = (msg.find("left: `"), msg.find("right: `")) {
-        if let (Some(left_end), Some(right_end)) = (
-            msg[left_index..].find("`,"),
-            msg[right_index..].find("`', "),
-        ) {
-            let left = &msg[(left_index + "left: `".len())..(left_index + left_end)];
-            let right = &msg[(right_index + "right: `".len())..(right_index + right_end)];
-            return Some((left, right));
+/// Execution counts of each instrumented line by file relative to the checkout.
+/// Relative `SF` paths are taken to be relative to `dir`.
+fn lcov_line_hits(
+    lcov: &str,
+    dir: &Path,
+    roots: &SourceRoots,
+) -> HashMap<String, BTreeMap<u64, u64>> {
+    let mut hits: HashMap<String, BTreeMap<u64, u64>> = HashMap::new();
+    let mut file = String::new();
+    for line in lcov.lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            file = roots.normalise(&dir.join(path).to_string_lossy()).0;
+        } else if let Some(data) = line.strip_prefix("DA:") {
+            let mut fields = data.split(',');
+            if let (Some(Ok(line)), Some(Ok(count))) = (
+                fields.next().map(str::parse::<u64>),
+                fields.next().map(str::parse::<u64>),
+            ) {
+                *hits
+                    .entry(file.clone())
+                    .or_default()
+                    .entry(line)
+                    .or_default() += count;
+            }
         }
     }
-    None
+    hits
 }
 
-fn parse_cov(cov: &str) -> (f64, u64, u64, u64) {
-    let stream = Deserializer::from_str(&cov);
-    for value in stream.into_iter() {
-        if let Ok(Value::Object(map)) = value {
-            let percent = if let Some(Value::Number(num)) = map.get("coveragePercent") {
-                num.as_f64().unwrap_or(0.)
-            } else {
-                0.
-            };
-            let lcov = if let Some(Value::Number(num)) = map.get("linesCovered") {
-                num.as_i64().unwrap_or(0) as u64
-            } else {
-                0
-            };
-            let lmiss = if let Some(Value::Number(num)) = map.get("linesMissed") {
-                num.as_i64().unwrap_or(0) as u64
-            } else {
-                0
+/// Coverage of the changed lines that are instrumented, and the uncovered ones
+/// as (file, first line, last line) runs.
+fn diff_coverage(
+    changed: &BTreeMap<String, BTreeSet<u64>>,
+    hits: &HashMap<String, BTreeMap<u64, u64>>,
+) -> (CoverageCount, Vec<(String, u64, u64)>) {
+    let mut count = CoverageCount::default();
+    let mut uncovered: Vec<(String, u64, u64)> = vec![];
+    for (file, lines) in changed {
+        let file_hits = match hits.get(file) {
+            Some(file_hits) => file_hits,
+            None => continue,
+        };
+        for line in lines {
+            let hit = match file_hits.get(line) {
+                Some(hit) => *hit,
+                None => continue,
             };
-            let ltot = if let Some(Value::Number(num)) = map.get("linesTotal") {
-                num.as_i64().unwrap_or(0) as u64
-            } else {
-                0
+            count.total += 1;
+            if hit > 0 {
+                count.covered += 1;
+                continue;
+            }
+            match uncovered.last_mut() {
+                Some((last_file, _, last)) if last_file == file && *last + 1 == *line => {
+                    *last = *line
+                }
+                _ => uncovered.push((file.clone(), *line, *line)),
+            }
+        }
+    }
+    (count, uncovered)
+}
+
+/// Branches taken out of those instrumented on each line (from `BRDA` records) by file
+/// relative to the checkout. Relative `SF` paths are taken to be relative to `dir`.
+fn lcov_branch_hits(
+    lcov: &str,
+    dir: &Path,
+    roots: &SourceRoots,
+) -> HashMap<String, BTreeMap<u64, CoverageCount>> {
+    let mut branches: HashMap<String, BTreeMap<u64, CoverageCount>> = HashMap::new();
+    let mut file = String::new();
+    for line in lcov.lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            file = roots.normalise(&dir.join(path).to_string_lossy()).0;
+        } else if let Some(data) = line.strip_prefix("BRDA:") {
+            // line,block,branch,taken (`-` if the block was never reached)
+            let fields: Vec<&str> = data.split(',').collect();
+            if let (Some(Ok(line)), Some(taken)) = (
+                fields.first().map(|line| line.parse::<u64>()),
+                fields.get(3),
+            ) {
+                let count = branches
+                    .entry(file.clone())
+                    .or_default()
+                    .entry(line)
+                    .or_default();
+                count.total += 1;
+                count.covered += taken.parse::<u64>().is_ok_and(|taken| taken > 0) as u64;
+            }
+        }
+    }
+    branches
+}
+
+/// A Cobertura report from the line and branch hits, with a package per workspace crate
+/// and a class per file. `timestamp` is in seconds since the unix epoch.
+fn cobertura_xml(
+    hits: &HashMap<String, BTreeMap<u64, u64>>,
+    branches: &HashMap<String, BTreeMap<u64, CoverageCount>>,
+    roots: &SourceRoots,
+    timestamp: u64,
+) -> String {
+    fn count<'a>(hits: impl Iterator<Item = &'a u64>) -> CoverageCount {
+        hits.fold(CoverageCount::default(), |count, hit| CoverageCount {
+            covered: count.covered + (*hit > 0) as u64,
+            total: count.total + 1,
+        })
+    }
+    fn branch_count<'a>(
+        files: impl Iterator<Item = &'a String>,
+        branches: &HashMap<String, BTreeMap<u64, CoverageCount>>,
+    ) -> CoverageCount {
+        files
+            .filter_map(|file| branches.get(file))
+            .flat_map(|lines| lines.values())
+            .fold(CoverageCount::default(), |total, count| CoverageCount {
+                covered: total.covered + count.covered,
+                total: total.total + count.total,
+            })
+    }
+    fn rate(count: &CoverageCount) -> String {
+        if count.total == 0 {
+            "0".to_string()
+        } else {
+            format!("{:.4}", count.percent() / 100.)
+        }
+    }
+    let mut packages: BTreeMap<&str, BTreeMap<&String, &BTreeMap<u64, u64>>> = BTreeMap::new();
+    for (file, lines) in hits {
+        let package = roots
+            .package_of(&roots.checkout.join(file))
+            .unwrap_or("external");
+        packages.entry(package).or_default().insert(file, lines);
+    }
+    let total = count(hits.values().flat_map(|lines| lines.values()));
+    let total_branches = branch_count(hits.keys(), branches);
+    let mut xml = format!(
+        "<?xml version=\"1.0\" ?>\n<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n<coverage lines-valid=\"{}\" lines-covered=\"{}\" line-rate=\"{:.4}\" branches-valid=\"{}\" branches-covered=\"{}\" branch-rate=\"{}\" complexity=\"0\" version=\"1.9\" timestamp=\"{}\">\n<sources>\n<source>{}</source>\n</sources>\n<packages>\n",
+        total.total,
+        total.covered,
+        total.percent() / 100.,
+        total_branches.total,
+        total_branches.covered,
+        rate(&total_branches),
+        timestamp,
+        escape_xml(&roots.checkout.to_string_lossy())
+    );
+    for (package, files) in packages {
+        let package_rate = count(files.values().flat_map(|lines| lines.values())).percent() / 100.;
+        xml.push_str(&format!(
+            "<package name=\"{}\" line-rate=\"{:.4}\" branch-rate=\"{}\" complexity=\"0\">\n<classes>\n",
+            escape_xml(package),
+            package_rate,
+            rate(&branch_count(files.keys().copied(), branches))
+        ));
+        for (file, lines) in files {
+            let name = Path::new(file)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            xml.push_str(&format!(
+                "<class name=\"{}\" filename=\"{}\" line-rate=\"{:.4}\" branch-rate=\"{}\" complexity=\"0\">\n<methods/>\n<lines>\n",
+                escape_xml(&name),
+                escape_xml(file),
+                count(lines.values()).percent() / 100.,
+                rate(&branch_count(std::iter::once(file), branches))
+            ));
+            let file_branches = branches.get(file);
+            for (line, hit) in lines {
+                match file_branches.and_then(|branches| branches.get(line)) {
+                    Some(taken) => xml.push_str(&format!(
+                        "<line number=\"{}\" hits=\"{}\" branch=\"true\" condition-coverage=\"{:.0}% ({}/{})\"/>\n",
+                        line,
+                        hit,
+                        taken.percent(),
+                        taken.covered,
+                        taken.total
+                    )),
+                    None => xml.push_str(&format!(
+                        "<line number=\"{}\" hits=\"{}\" branch=\"false\"/>\n",
+                        line, hit
+                    )),
+                }
+            }
+            xml.push_str("</lines>\n</class>\n");
+        }
+        xml.push_str("</classes>\n</package>\n");
+    }
+    xml.push_str("</packages>\n</coverage>\n");
+    xml
+}
+
+/// An inspection for each run of changed lines that no test executed.
+fn uncovered_inspections(brand: &str, uncovered: &[(String, u64, u64)]) -> String {
+    if uncovered.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "##{}[inspectionType id='uncovered-change' category='coverage' name='uncovered-change' description='Changed lines that are not covered by tests.']\n",
+        brand
+    );
+    for (file, first, last) in uncovered {
+        let message = if first == last {
+            format!("line {} is not covered by tests", first)
+        } else {
+            format!("lines {}-{} are not covered by tests", first, last)
+        };
+        out.push_str(&format!(
+            "##{}[inspection typeId='uncovered-change' message='{}' file='{}' line='{}' SEVERITY='WARNING']\n",
+            brand,
+            escape_message(&message),
+            escape_message(file),
+            first
+        ));
+    }
+    out
+}
+
+/// Line coverage the run has to reach (`--cover-min`) and how far it may
+/// fall below the best seen so far (`--cover-max-drop` and `--cover-baseline`).
+#[derive(Default)]
+struct CoverageGate {
+    /// Minimum percentage by crate name ("" for the total).
+    minimums: HashMap<String, f64>,
+    /// Minimum percentage of changed lines covered.
+    diff_minimum: Option<f64>,
+    /// Percentage points coverage may drop by compared with the baseline.
+    max_drop: f64,
+    /// Where the best coverage so far is kept.
+    baseline: Option<PathBuf>,
+}
+
+impl CoverageGate {
+    /// Raises a build problem for each requirement not met and, if they all are,
+    /// ratchets the baseline up. Returns false if coverage is too low.
+    fn check(&self, brand: &str, coverage: &Coverage) -> std::io::Result<bool> {
+        let current = coverage_counts(coverage);
+        let previous = match &self.baseline {
+            Some(path) => std::fs::read_to_string(path)
+                .ok()
+                .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+                .map(|json| parse_coverage_baseline(&json))
+                .unwrap_or_default(),
+            None => BTreeMap::new(),
+        };
+        let mut failures = coverage_failures(&current, &self.minimums, self.max_drop, &previous);
+        let changed = &coverage.changed_lines;
+        match self.diff_minimum {
+            Some(minimum) if changed.total > 0 && changed.percent() < minimum => failures.push((
+                "changed-lines".to_string(),
+                format!(
+                    "coverage of changed lines is {:.2}% which is below the minimum of {}%",
+                    changed.percent(),
+                    minimum
+                ),
+            )),
+            _ => {}
+        }
+        for (name, failure) in &failures {
+            println!(
+                "##{}[buildProblem description='{}' identity='coverage.{}']",
+                brand,
+                escape_message(failure),
+                if name.is_empty() { "total" } else { name }
+            );
+        }
+        if let (Some(path), true) = (&self.baseline, failures.is_empty()) {
+            let mut best = previous;
+            for (name, count) in current {
+                let entry = best.entry(name).or_insert(count);
+                if count.percent() > entry.percent() {
+                    *entry = count;
+                }
+            }
+            std::fs::write(
+                path,
+                serde_json::to_string_pretty(&coverage_baseline_json(&best))?,
+            )?;
+        }
+        Ok(failures.is_empty())
+    }
+
+    /// Whether there's anything to check coverage against.
+    fn is_configured(&self) -> bool {
+        !self.minimums.is_empty() || self.diff_minimum.is_some() || self.baseline.is_some()
+    }
+}
+
+/// `80,mycrate=70`: a bare number is the minimum for the total.
+fn parse_coverage_minimums(spec: &str) -> HashMap<String, f64> {
+    spec.split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let (name, percent) = entry.split_once('=').unwrap_or(("", entry));
+            match percent.trim_end_matches('%').parse() {
+                Ok(percent) => Some((name.to_string(), percent)),
+                Err(_) => {
+                    eprintln!(
+                        "cargo-service-message: unknown coverage minimum '{}'",
+                        entry
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Line coverage by crate name ("" for the total).
+fn coverage_counts(coverage: &Coverage) -> BTreeMap<String, CoverageCount> {
+    let mut counts: BTreeMap<String, CoverageCount> = coverage.by_crate.iter().cloned().collect();
+    counts.insert(String::new(), coverage.lines);
+    counts
+}
+
+/// Percentages are recomputed from line counts on both sides but still
+/// allow for rounding so unchanged coverage never counts as a drop.
+const COVERAGE_EPSILON: f64 = 1e-9;
+
+/// What falls short of the minimums or dropped too far, by crate name ("" for the total).
+fn coverage_failures(
+    current: &BTreeMap<String, CoverageCount>,
+    minimums: &HashMap<String, f64>,
+    max_drop: f64,
+    previous: &BTreeMap<String, CoverageCount>,
+) -> Vec<(String, String)> {
+    let mut failures = vec![];
+    let mut missing: Vec<&String> = minimums
+        .keys()
+        .filter(|name| !current.contains_key(*name))
+        .collect();
+    missing.sort();
+    for name in missing {
+        failures.push((
+            name.clone(),
+            format!(
+                "no line coverage was found for {} which has a minimum of {}%",
+                name, minimums[name]
+            ),
+        ));
+    }
+    for (name, count) in current {
+        let percent = count.percent();
+        let what = if name.is_empty() {
+            "line coverage".to_string()
+        } else {
+            format!("line coverage of {}", name)
+        };
+        if let Some(minimum) = minimums
+            .get(name)
+            .filter(|minimum| percent + COVERAGE_EPSILON < **minimum)
+        {
+            failures.push((
+                name.clone(),
+                format!(
+                    "{} is {:.2}% which is below the minimum of {}%",
+                    what, percent, minimum
+                ),
+            ));
+        }
+        if let Some(before) = previous
+            .get(name)
+            .map(CoverageCount::percent)
+            .filter(|before| before - percent > max_drop + COVERAGE_EPSILON)
+        {
+            failures.push((
+                name.clone(),
+                format!(
+                    "{} dropped from {:.2}% to {:.2}% (more than the {}% allowed)",
+                    what, before, percent, max_drop
+                ),
+            ));
+        }
+    }
+    failures
+}
+
+/// The baseline keeps line counts rather than percentages so that nothing is lost in the round trip.
+fn parse_coverage_baseline(json: &Value) -> BTreeMap<String, CoverageCount> {
+    let count = |lines: &Value| -> Option<CoverageCount> {
+        Some(CoverageCount {
+            covered: lines["covered"].as_u64()?,
+            total: lines["total"].as_u64()?,
+        })
+    };
+    let mut counts = BTreeMap::new();
+    if let Some(total) = count(&json["total"]) {
+        counts.insert(String::new(), total);
+    }
+    if let Some(crates) = json["crates"].as_object() {
+        for (name, lines) in crates {
+            if let Some(lines) = count(lines) {
+                counts.insert(name.clone(), lines);
+            }
+        }
+    }
+    counts
+}
+
+fn coverage_baseline_json(counts: &BTreeMap<String, CoverageCount>) -> Value {
+    let json = |lines: &CoverageCount| serde_json::json!({ "covered": lines.covered, "total": lines.total });
+    serde_json::json!({
+        "total": counts.get("").map(json),
+        "crates": counts
+            .iter()
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, lines)| (name.clone(), json(lines)))
+            .collect::<serde_json::Map<_, _>>(),
+    })
+}
+
+/// Merges the raw profiles and reports on them with `llvm-cov`.
+fn llvm_cov_report(report_dir: &Path, filter: &CoverageFilter) -> Option<Coverage> {
+    let data = coverage_data_dir();
+    let profraws: Vec<PathBuf> = std::fs::read_dir(&data)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension() == Some("profraw".as_ref()))
+        .collect();
+    if profraws.is_empty() {
+        println!("coverage: no profiles found in {}", data.display());
+        return None;
+    }
+
+    let profdata = data.join("coverage.profdata");
+    let merged = Command::new(llvm_tool("llvm-profdata")?)
+        .arg("merge")
+        .arg("-sparse")
+        .args(&profraws)
+        .arg("-o")
+        .arg(&profdata)
+        .status();
+    if !matches!(merged, Ok(status) if status.success()) {
+        println!("coverage: llvm-profdata merge failed: {:?}", merged);
+        return None;
+    }
+
+    let objects: Vec<String> = std::fs::read_to_string(data.join("objects.txt"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|object| Path::new(object).exists())
+        .map(str::to_string)
+        .collect();
+    if objects.is_empty() {
+        println!("coverage: no instrumented binaries found");
+        return None;
+    }
+
+    let mut html = llvm_cov_cmd("show", &profdata, &objects, filter)?;
+    html.arg("-format=html")
+        .arg(format!("-output-dir={}", report_dir.display()));
+    println!("{:?}", html);
+    if let Err(err) = html.output() {
+        eprintln!("llvm-cov error while processing coverage: {}", err);
+    }
+
+    // Line by line hits for diff coverage.
+    let mut lcov = llvm_cov_cmd("export", &profdata, &objects, filter)?;
+    lcov.arg("-format=lcov");
+    match lcov.output() {
+        Ok(output) if output.status.success() => {
+            if let Err(err) = std::fs::write(report_dir.join("lcov.info"), output.stdout) {
+                println!("coverage: couldn't write lcov: {}", err);
+            }
+        }
+        failed => println!(
+            "llvm-cov lcov export failed: {:?}",
+            failed.map(|o| o.status)
+        ),
+    }
+
+    let mut export = llvm_cov_cmd("export", &profdata, &objects, filter)?;
+    export.arg("-format=text").arg("-summary-only");
+    match export.output() {
+        Ok(output) if output.status.success() => {
+            Some(parse_llvm_cov(&String::from_utf8_lossy(&output.stdout)))
+        }
+        Ok(output) => {
+            println!("llvm-cov export failed - output follows:");
+            println!("{}", &String::from_utf8_lossy(&output.stderr));
+            println!("-- fin --");
+            None
+        }
+        Err(err) => {
+            println!("coverage failed to execute: {:?}", err);
+            None
+        }
+    }
+}
+
+/// `llvm-cov <subcommand>` for the given profile and binaries, ignoring 3rd party and std sources
+/// and anything the filter excludes.
+fn llvm_cov_cmd(
+    subcommand: &str,
+    profdata: &Path,
+    objects: &[String],
+    filter: &CoverageFilter,
+) -> Option<Command> {
+    let cwd = std::env::current_dir().ok()?;
+    let mut cmd = Command::new(llvm_tool("llvm-cov")?);
+    cmd.arg(subcommand)
+        .arg(format!("-instr-profile={}", profdata.display()))
+        .arg(format!(
+            "-ignore-filename-regex={}",
+            coverage_ignore_regex(filter, &cwd)
+        ));
+    for (i, object) in objects.iter().enumerate() {
+        if i > 0 {
+            cmd.arg("-object");
+        }
+        cmd.arg(object);
+    }
+    // llvm-cov can only be told which sources to keep by listing them.
+    if !filter.include.is_empty() {
+        cmd.args(filter.sources(&cwd));
+    }
+    Some(cmd)
+}
+
+fn coverage_ignore_regex(filter: &CoverageFilter, dir: &Path) -> String {
+    let mut ignore = vec![
+        "^/rustc/".to_string(),
+        format!("^{}/", regex_escape(&target_dir().to_string_lossy())),
+    ];
+    if let Ok(cargo_home) = cargo_home() {
+        ignore.push(format!("^{}/", regex_escape(&cargo_home)));
+    }
+    for glob in &filter.exclude {
+        ignore.push(format!(
+            "^{}/{}$",
+            regex_escape(&dir.to_string_lossy()),
+            glob_regex(glob)
+        ));
+    }
+    ignore.join("|")
+}
+
+/// Which sources count towards coverage: `--cover-include` and `--cover-exclude`
+/// globs (comma separated, relative to the current directory) on top of the default exclusions.
+struct CoverageFilter {
+    /// If any, only files matching one of these.
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+/// Test, bench and example code isn't production code, and vendored or
+/// generated (`OUT_DIR`) code isn't ours.
+const DEFAULT_COVERAGE_EXCLUDES: &[&str] = &[
+    "**/tests/**",
+    "**/benches/**",
+    "**/examples/**",
+    "**/vendor/**",
+    "target/**",
+];
+
+impl CoverageFilter {
+    fn parse(params: &str) -> CoverageFilter {
+        let globs = |name: &str| -> Vec<String> {
+            param(params, name)
+                .unwrap_or("")
+                .split(',')
+                .filter(|glob| !glob.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let mut exclude: Vec<String> = DEFAULT_COVERAGE_EXCLUDES
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect();
+        exclude.extend(globs("--cover-exclude"));
+        CoverageFilter {
+            include: globs("--cover-include"),
+            exclude,
+        }
+    }
+
+    fn keeps(&self, file: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, file)))
+            && !self.exclude.iter().any(|glob| glob_match(glob, file))
+    }
+
+    /// The rust sources under `dir` that are kept.
+    fn sources(&self, dir: &Path) -> Vec<PathBuf> {
+        fn walk(dir: &Path, root: &Path, filter: &CoverageFilter, sources: &mut Vec<PathBuf>) {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => return,
             };
-            return (percent, lcov, lmiss, ltot);
+            for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+                let hidden = path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+                if hidden || path == target_dir() {
+                    continue;
+                }
+                if path.is_dir() {
+                    walk(&path, root, filter, sources);
+                } else if path.extension() == Some("rs".as_ref()) {
+                    let relative = path.strip_prefix(root).unwrap_or(&path);
+                    if filter.keeps(&relative.to_string_lossy().replace('\\', "/")) {
+                        sources.push(path);
+                    }
+                }
+            }
         }
+        let mut sources = vec![];
+        walk(dir, dir, self, &mut sources);
+        sources.sort();
+        sources
     }
-    (0., 0, 0, 0)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    //(Benche isn't stable still!)
-    //use test::Bencher;
+/// Matches a path against a glob where `*` and `?` stay within a directory and `**` crosses them.
+fn glob_match(glob: &str, path: &str) -> bool {
+    fn matches(glob: &[u8], path: &[u8]) -> bool {
+        match glob {
+            [] => path.is_empty(),
+            [b'*', b'*', rest @ ..] => match rest {
+                [] => true,
+                [b'/', rest @ ..] => (0..=path.len())
+                    .any(|i| (i == 0 || path[i - 1] == b'/') && matches(rest, &path[i..])),
+                _ => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            },
+            [b'*', rest @ ..] => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != b'/')
+                .any(|i| matches(rest, &path[i..])),
+            [b'?', rest @ ..] => {
+                matches!(path.first(), Some(c) if *c != b'/') && matches(rest, &path[1..])
+            }
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    matches(glob.as_bytes(), path.as_bytes())
+}
 
-    //Bench not stable:
-    // #[bench]
-    // fn example_bench_add_two(b: &mut Bencher) {
-    //     b.iter(|| {
-    //         print!("hi");
-    //     });
-    // }
+/// The regex equivalent of `glob_match`.
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex_escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+fn regex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The grcov html and covdir reports.
+fn grcov_report(report_dir: &Path, mode: &str, filter: &CoverageFilter) -> Option<Coverage> {
+    let target = target_dir();
+    let binaries = coverage_target_dir().join(mode);
+    let mut grcov = grcov_cmd(
+        &coverage_data_dir(),
+        &binaries,
+        "html",
+        report_dir.to_str().unwrap(),
+        filter,
+    );
+    println!("{:?}", grcov);
+    if let Err(err) = grcov.output() {
+        eprintln!("grcov error while processing coverage: {}", err);
+    }
+    //An attempt to override the css file...
+    //std::thread::sleep(Duration::new(1, 0));
+    // use std::fs::File;
+    // use std::io::Write;
+    // let file_name = std::env::current_dir()
+    //     .unwrap()
+    //     .join("target/coverage/grcov.css");
+    // println!("going to {:?}", &file_name);
+
+    // if let Err(rr) = std::fs::remove_file(&file_name) {
+    //     eprintln!("Error {:?}", rr);
+    // }
+    // println!("did {:?}", &file_name);
+    // println!("{}", CSS);
+    // {
+    //     let mut f = File::create(file_name).expect("Unable to create file");
+    //     f.write_all(CSS.as_bytes()).expect("Unable to write data");
+    // }
+    // f.drop();
+
+    // covdir only has line counts so functions and branches come from lcov.
+    let lcov_filename = report_dir.join("lcov.info");
+    let mut grcov = grcov_cmd(
+        &coverage_data_dir(),
+        &binaries,
+        "lcov",
+        lcov_filename.to_str().unwrap(),
+        filter,
+    );
+    if let Err(err) = grcov.output() {
+        eprintln!("grcov error while processing coverage: {}", err);
+    }
+
+    let json_filename: PathBuf = target.join("coverage.json");
+    let mut grcov = grcov_cmd(
+        &coverage_data_dir(),
+        &binaries,
+        "covdir",
+        json_filename.to_str().unwrap(),
+        filter,
+    );
+    match grcov.output() {
+        Ok(output) => {
+            if let Ok(coverage) = std::fs::read_to_string(json_filename) {
+                let lcov = std::fs::read_to_string(lcov_filename).unwrap_or_default();
+                let cwd = std::env::current_dir().unwrap();
+                let mut by_file = Vec::new();
+                if let Ok(covdir) = serde_json::from_str::<Value>(&coverage) {
+                    covdir_files(&covdir, &cwd, &mut by_file);
+                }
+                Some(Coverage {
+                    lines: parse_cov(&coverage),
+                    by_file,
+                    ..parse_lcov(&lcov)
+                })
+            } else {
+                println!("Coverage didn't produce json file - output follows:");
+                println!("{}", &String::from_utf8_lossy(&output.stdout));
+                println!("{}", &String::from_utf8_lossy(&output.stderr));
+                println!("-- fin --");
+                None
+            }
+        }
+        Err(err) => {
+            println!("coverage failed to execute: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Finds `--name` or `--name=value` amongst the SERVICE_MESSAGE params.
+/// A bare flag gives an empty value.
+fn param<'a>(params: &'a str, name: &str) -> Option<&'a str> {
+    params.split_whitespace().find_map(|p| {
+        if p == name {
+            Some("")
+        } else {
+            p.strip_prefix(name)
+                .and_then(|value| value.strip_prefix('='))
+        }
+    })
+}
+
+/// Writes a file, creating the directory it goes in if need be.
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents)
+}
+
+/// Writes a report and publishes it as a TeamCity artifact. A report that can't be
+/// written is logged rather than failing the build step.
+fn write_report(brand: &str, path: &Path, contents: impl AsRef<[u8]>) {
+    match write_file(path, contents) {
+        Ok(()) => println!("##{}[publishArtifacts '{}']", brand, path.display()),
+        Err(err) => println!(
+            "cargo-service-message: could not write {}: {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+/// Where to write a report: the given path or else `default_name` in the target dir.
+fn report_path(path: &str, default_name: &str) -> PathBuf {
+    if path.is_empty() {
+        target_dir().join(default_name)
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+fn target_dir() -> PathBuf {
+    let cwd = std::env::current_dir().unwrap();
+    // Made absolute so that it can be compared with and matched against absolute paths.
+    env::var("CARGO_TARGET_DIR")
+        .map(|s| cwd.join(PathBuf::from_str(&s).unwrap()))
+        .unwrap_or_else(|_| cwd.join("target"))
+}
+
+/// Command to generate coverage
+fn grcov_cmd(
+    input_dir: &Path,
+    binary_path: &Path,
+    output_type: &str,
+    output_dir: &str,
+    filter: &CoverageFilter,
+) -> Command {
+    let mut grcov = Command::new("grcov");
+    grcov
+        .arg(input_dir.to_str().unwrap())
+        .arg("--binary-path")
+        .arg(binary_path)
+        .arg("-s")
+        .arg(".")
+        .arg("-t")
+        .arg(output_type)
+        .arg("--llvm")
+        .arg("--branch")
+        .arg("--ignore-not-existing")
+        .arg("-o")
+        .arg(output_dir)
+        // Lines between `// coverage:off` and `// coverage:on` don't count.
+        .arg("--excl-start")
+        .arg("coverage:off")
+        .arg("--excl-stop")
+        .arg("coverage:on")
+        .arg("--excl-line")
+        .arg("coverage:ignore-line");
+
+    // Ignore 3rd party crates
+    if let Ok(cargo_home) = cargo_home() {
+        grcov.arg("--ignore").arg(format!("{}/**", cargo_home));
+    }
+    grcov
+        .arg("--ignore")
+        .arg(format!("{}/**", target_dir().display()));
+    for glob in &filter.exclude {
+        grcov.arg("--ignore").arg(glob);
+    }
+    for glob in &filter.include {
+        grcov.arg("--keep-only").arg(glob);
+    }
+    grcov
+}
+
+//static CSS: &str = include_str!("grcov.css");
+
+#[derive(Default)]
+struct Context {
+    debug: bool,
+    brand: String,
+    min_threshold: f64,
+    cargo_cmd: String,
+    /// When set test events are rendered as TAP rather than service messages.
+    tap: Option<Tap>,
+    /// Write a Code Climate (GitLab code quality) report here.
+    codeclimate: Option<PathBuf>,
+    /// Write a Checkstyle XML report here.
+    checkstyle: Option<PathBuf>,
+    /// Used to make diagnostic paths relative to the checkout.
+    roots: SourceRoots,
+    /// Don't report warnings in files outside of the checkout.
+    drop_external: bool,
+    /// Inspection severity overrides by lint or lint group name.
+    severities: HashMap<String, String>,
+    /// Which group each lint is in.
+    lint_groups: LintGroups,
+    /// What to do about a diagnostic by lint or lint group name.
+    policy: HashMap<String, Outcome>,
+    /// A diagnostic with the `fail` policy was seen.
+    policy_failed: bool,
+    /// Known diagnostics that shouldn't be reported.
+    baseline: Option<Baseline>,
+    /// Inspection types already declared to TeamCity this run.
+    inspection_types: HashSet<String>,
+    /// Every warning and error seen this run.
+    diagnostics: Vec<Diagnostic>,
+    /// Index into `diagnostics` by (code, level, primary span, message).
+    seen_diagnostics: HashMap<String, usize>,
+    /// Pass/fail counts of each test binary.
+    suites: Vec<Value>,
+    /// Names for the test suites from cargo's output.
+    suite_names: SuiteNames,
+    /// Name of the test suite being run.
+    suite_name: String,
+    /// (test name, seconds)
+    test_times: Vec<(String, f64)>,
+    /// (test name, output including the panic message)
+    failures: Vec<(String, String)>,
+    /// (unit name, compile mode, seconds)
+    unit_times: Vec<(String, String, f64)>,
+    /// Binaries built this run (needed to map coverage profiles back to source).
+    executables: Vec<String>,
+}
+
+/// Where a source file lives.
+#[derive(Clone, Debug, PartialEq)]
+enum SourceKind {
+    Checkout,
+    /// Dependency sources under `cargo_home()`.
+    Registry,
+    /// Build script output (`OUT_DIR`) and the like under the target dir.
+    Generated,
+    External,
+}
+
+/// The directories that source paths are resolved against.
+/// (All empty means paths are left as rustc printed them.)
+#[derive(Default)]
+struct SourceRoots {
+    /// rustc prints workspace members' paths relative to this.
+    workspace: PathBuf,
+    /// Paths are reported relative to this so that TeamCity can link them to the VCS.
+    checkout: PathBuf,
+    cargo_home: Option<PathBuf>,
+    target: PathBuf,
+    /// Workspace members and the directories holding their Cargo.toml.
+    packages: Vec<(String, PathBuf)>,
+}
+
+impl SourceRoots {
+    fn detect() -> SourceRoots {
+        let cwd = std::env::current_dir().unwrap();
+        let metadata = Command::new("cargo")
+            .args(["metadata", "--format-version", "1", "--no-deps"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .and_then(|output| serde_json::from_slice::<Value>(&output.stdout).ok())
+            .unwrap_or_default();
+        let workspace = metadata["workspace_root"]
+            .as_str()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| cwd.clone());
+        let packages = metadata["packages"]
+            .as_array()
+            .map(|packages| {
+                packages
+                    .iter()
+                    .filter_map(|package| {
+                        let manifest = Path::new(package["manifest_path"].as_str()?);
+                        Some((
+                            package["name"].as_str()?.to_string(),
+                            manifest.parent()?.to_path_buf(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let checkout = Command::new("git")
+            .args(["rev-parse", "--show-toplevel"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+            .unwrap_or_else(|| workspace.clone());
+        SourceRoots {
+            workspace,
+            checkout,
+            cargo_home: cargo_home().ok().map(PathBuf::from),
+            target: target_dir(),
+            packages,
+        }
+    }
+
+    /// The workspace member a source file belongs to (the most deeply nested one).
+    fn package_of(&self, path: &Path) -> Option<&str> {
+        self.packages
+            .iter()
+            .filter(|(_, dir)| path.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Where a file rustc reported can be read from.
+    fn resolve(&self, file: &str) -> PathBuf {
+        self.workspace.join(file)
+    }
+
+    /// Classifies a file rustc reported and, if it's in the checkout,
+    /// makes it relative to the checkout root.
+    fn normalise(&self, file: &str) -> (String, SourceKind) {
+        if self.checkout.as_os_str().is_empty() {
+            return (file.to_string(), SourceKind::Checkout);
+        }
+        let path = self.workspace.join(file);
+        let kind = if matches!(&self.cargo_home, Some(home) if path.starts_with(home)) {
+            SourceKind::Registry
+        } else if path.starts_with(&self.target) {
+            SourceKind::Generated
+        } else if path.starts_with(&self.checkout) {
+            SourceKind::Checkout
+        } else {
+            SourceKind::External
+        };
+        match path.strip_prefix(&self.checkout) {
+            Ok(relative) if kind == SourceKind::Checkout => {
+                (relative.to_string_lossy().replace('\\', "/"), kind)
+            }
+            _ => (path.to_string_lossy().into_owned(), kind),
+        }
+    }
+}
+
+/// A compiler warning or error as reported to the exporters.
+struct Diagnostic {
+    /// Name of the crate being compiled.
+    package: String,
+    code: String,
+    /// The lint group (or `clippy` / `rustc`).
+    category: String,
+    level: String,
+    message: String,
+    file: String,
+    line: u64,
+    /// Source text of the primary span.
+    snippet: String,
+    /// All the places rustc marked as primary for this diagnostic.
+    primary_spans: Vec<Span>,
+    /// How many compilation units reported it.
+    targets: usize,
+    /// False if the diagnostic is in the `--baseline`.
+    is_new: bool,
+    /// Fixes rustc is confident can be applied automatically.
+    suggestions: Vec<Suggestion>,
+}
+
+/// A `MachineApplicable` replacement suggested by rustc or clippy.
+#[derive(Clone)]
+struct Suggestion {
+    /// Where the file can be read from.
+    path: PathBuf,
+    /// The file relative to the checkout.
+    file: String,
+    kind: SourceKind,
+    line: u64,
+    column: u64,
+    byte_start: usize,
+    byte_end: usize,
+    replacement: String,
+}
+
+struct Span {
+    file: String,
+    line: u64,
+    column: u64,
+    line_end: u64,
+    column_end: u64,
+    label: Option<String>,
+}
+
+impl Diagnostic {
+    /// Identifies a diagnostic independently of its line number so that
+    /// edits elsewhere in a file don't make it look like a new issue.
+    fn fingerprint(&self) -> String {
+        let snippet = self
+            .snippet
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "{:016x}",
+            fnv1a(&format!("{}\0{}\0{}", self.code, self.file, snippet))
+        )
+    }
+}
+
+/// FNV-1a: a hash that's stable across rust releases (unlike `DefaultHasher`).
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Counts of warnings and errors in total, per crate and per lint so that code health can be charted.
+fn diagnostic_statistics(ctx: &Context, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut totals: BTreeMap<String, usize> = BTreeMap::new();
+    totals.insert("diagnostics.warnings".to_string(), 0);
+    totals.insert("diagnostics.errors".to_string(), 0);
+    for diag in &ctx.diagnostics {
+        let level = if diag.level == "error" {
+            "errors"
+        } else {
+            "warnings"
+        };
+        for key in &[
+            format!("diagnostics.{}", level),
+            format!("diagnostics.{}.crate.{}", level, diag.package),
+            format!("diagnostics.lint.{}", diag.code),
+        ] {
+            *totals.entry(key.clone()).or_default() += 1;
+        }
+    }
+    for (key, value) in totals {
+        writeln!(
+            out,
+            "##{}[buildStatisticValue key='{}' value='{}']",
+            ctx.brand, key, value
+        )?;
+    }
+    Ok(())
+}
+
+/// Inspections go out when a diagnostic is first seen, so the number of targets it turned up
+/// in is only known once the build is done.
+fn multi_target_notes(diagnostics: &[Diagnostic], out: &mut dyn Write) -> std::io::Result<()> {
+    for diag in diagnostics.iter().filter(|diag| diag.targets > 1) {
+        writeln!(
+            out,
+            "{}:{}: {}{}",
+            diag.file,
+            diag.line,
+            diag.message,
+            targets_note(diag)
+        )?;
+    }
+    Ok(())
+}
+
+/// Diagnostics that were already there, so only new ones get reported.
+#[derive(Default)]
+struct Baseline {
+    /// Fingerprints not (yet) seen this run, with how many times each is expected.
+    remaining: HashMap<String, usize>,
+    new: usize,
+    existing: usize,
+}
+
+impl Baseline {
+    fn load(path: &Path) -> Result<Baseline, Box<dyn Error>> {
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let mut remaining = HashMap::new();
+        if let Some(Value::Array(diagnostics)) = json.get("diagnostics") {
+            for diag in diagnostics {
+                if let Some(Value::String(fingerprint)) = diag.get("fingerprint") {
+                    *remaining.entry(fingerprint.clone()).or_default() += 1;
+                }
+            }
+        }
+        Ok(Baseline {
+            remaining,
+            ..Baseline::default()
+        })
+    }
+
+    /// Returns true if the diagnostic is new.
+    fn classify(&mut self, fingerprint: &str) -> bool {
+        match self.remaining.get_mut(fingerprint) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                self.existing += 1;
+                false
+            }
+            _ => {
+                self.new += 1;
+                true
+            }
+        }
+    }
+
+    /// Diagnostics in the baseline that weren't seen this run.
+    fn fixed(&self) -> usize {
+        self.remaining.values().sum()
+    }
+}
+
+/// The default baseline lives next to the workspace so that it can be checked in.
+fn baseline_path(path: &str, workspace: &Path) -> PathBuf {
+    if path.is_empty() {
+        workspace.join("service-message-baseline.json")
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+fn baseline_json(diagnostics: &[Diagnostic]) -> String {
+    let diagnostics: Vec<Value> = diagnostics
+        .iter()
+        .filter(|diag| diag.level == "warning")
+        .map(|diag| {
+            serde_json::json!({
+                "fingerprint": diag.fingerprint(),
+                "code": diag.code,
+                "file": diag.file,
+                "message": diag.message,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&serde_json::json!({
+        "version": 1,
+        "diagnostics": diagnostics,
+    }))
+    .unwrap()
+}
+
+/// The Code Climate category closest to a diagnostic's lint group.
+fn code_climate_category(diag: &Diagnostic) -> &'static str {
+    match diag.category.as_str() {
+        _ if diag.level == "error" => "Bug Risk",
+        "clippy::correctness" | "clippy::suspicious" | "future_incompatible" => "Bug Risk",
+        "clippy::complexity" => "Complexity",
+        "clippy::perf" => "Performance",
+        _ => "Style",
+    }
+}
+
+/// Code Climate JSON as understood by GitLab merge request widgets.
+fn code_climate_report(diagnostics: &[Diagnostic]) -> String {
+    let issues: Vec<Value> = diagnostics
+        .iter()
+        .map(|diag| {
+            serde_json::json!({
+                "type": "issue",
+                "check_name": diag.code,
+                "description": format!("{}{}", diag.message, targets_note(diag)),
+                "categories": [code_climate_category(diag)],
+                "fingerprint": diag.fingerprint(),
+                "severity": if diag.level == "error" { "critical" } else { "minor" },
+                "location": {
+                    "path": diag.file,
+                    "lines": { "begin": diag.line }
+                }
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&issues).unwrap()
+}
+
+/// Checkstyle XML (as imported by Jenkins warnings-ng) with one `error` per primary span.
+fn checkstyle_report(diagnostics: &[Diagnostic]) -> String {
+    let mut by_file: BTreeMap<&str, Vec<(&Span, &Diagnostic)>> = BTreeMap::new();
+    for diag in diagnostics {
+        for span in &diag.primary_spans {
+            by_file.entry(&span.file).or_default().push((span, diag));
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<checkstyle version=\"4.3\">\n");
+    for (file, errors) in by_file {
+        xml.push_str(&format!("  <file name=\"{}\">\n", escape_xml(file)));
+        for (span, diag) in errors {
+            xml.push_str(&format!(
+                "    <error line=\"{}\" column=\"{}\" severity=\"{}\" message=\"{}\" source=\"{}\"/>\n",
+                span.line,
+                span.column,
+                diag.level,
+                escape_xml(&format!("{}{}", diag.message, targets_note(diag))),
+                escape_xml(&diag.code)
+            ));
+        }
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</checkstyle>\n");
+    xml
+}
+
+/// Running state of the TAP (Test Anything Protocol) output.
+/// Each libtest suite (i.e. each test binary) becomes a TAP 14 subtest.
+/// TAP is kept apart from the build log and written to its own file.
+#[derive(Default)]
+struct Tap {
+    suites: usize,
+    tests: usize,
+    /// Name of the suite being run.
+    current: String,
+    out: Vec<u8>,
+}
+
+impl Tap {
+    fn suite_started(
+        &mut self,
+        name: &str,
+        event: &Map<String, Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.suites == 0 {
+            writeln!(self.out, "TAP version 14")?;
+        }
+        self.suites += 1;
+        self.tests = 0;
+        writeln!(self.out, "# Subtest: {}", name)?;
+        if let Some(Value::Number(count)) = event.get("test_count") {
+            writeln!(self.out, "    1..{}", count)?;
+        }
+        self.current = name.to_string();
+        Ok(())
+    }
+
+    fn suite_finished(&mut self, ok: bool) -> Result<(), Box<dyn Error>> {
+        writeln!(
+            self.out,
+            "{} {} - {}",
+            if ok { "ok" } else { "not ok" },
+            self.suites,
+            self.current
+        )?;
+        Ok(())
+    }
+
+    fn test_event(
+        &mut self,
+        event_type: &str,
+        name: &str,
+        event: &Map<String, Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        let out = &mut self.out;
+        match event_type {
+            "ok" => {
+                self.tests += 1;
+                writeln!(out, "    ok {} - {}", self.tests, name)?;
+                if let Some(duration) = parse_exec_time(event) {
+                    writeln!(out, "      ---")?;
+                    writeln!(out, "      duration_ms: {:.3}", duration * 1000.)?;
+                    writeln!(out, "      ...")?;
+                }
+            }
+            "ignored" => {
+                self.tests += 1;
+                let reason = if let Some(Value::String(reason)) = event.get("message") {
+                    reason
+                } else {
+                    "ignored"
+                };
+                writeln!(out, "    ok {} - {} # SKIP {}", self.tests, name, reason)?;
+            }
+            "failed" => {
+                self.tests += 1;
+                let stdout = if let Some(Value::String(stdout)) = event.get("stdout") {
+                    stdout
+                } else {
+                    ""
+                };
+                writeln!(out, "    not ok {} - {}", self.tests, name)?;
+                writeln!(out, "      ---")?;
+                writeln!(out, "      message: 'test failed'")?;
+                writeln!(out, "      severity: fail")?;
+                if !stdout.is_empty() {
+                    writeln!(out, "      stdout: {}", yaml_string(stdout, "        "))?;
+                }
+                if let Some((left, right)) = find_comparison(stdout) {
+                    writeln!(out, "      expected: {}", yaml_string(left, "        "))?;
+                    writeln!(out, "      actual: {}", yaml_string(right, "        "))?;
+                }
+                if let Some(duration) = parse_exec_time(event) {
+                    writeln!(out, "      duration_ms: {:.3}", duration * 1000.)?;
+                }
+                writeln!(out, "      ...")?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The TAP output with the top level plan now all suites have been seen.
+    fn finish(&mut self) -> Vec<u8> {
+        if self.suites > 0 {
+            let _ = writeln!(self.out, "1..{}", self.suites);
+        }
+        std::mem::take(&mut self.out)
+    }
+}
+
+/// Renders a string as a YAML scalar, using a literal block for multi-line text.
+fn yaml_string(value: &str, indent: &str) -> String {
+    if value.contains('\n') {
+        let mut block = "|-".to_string();
+        for line in value.trim_end().lines() {
+            block.push('\n');
+            block.push_str(indent);
+            block.push_str(line);
+        }
+        block
+    } else {
+        format!("'{}'", value.replace("'", "''"))
+    }
+}
+
+/// Test durations in seconds. (Only present when run with `--report-time`.)
+fn parse_exec_time(event: &Map<String, Value>) -> Option<f64> {
+    match event.get("exec_time") {
+        Some(Value::Number(secs)) => secs.as_f64(),
+        Some(Value::String(secs)) => secs.trim_end_matches('s').parse().ok(),
+        _ => None,
+    }
+}
+
+/// Processes a line of output from cargo and potentially augments that output with service messages.
+/// Returns true if inspection was rasied.
+fn process(
+    ctx: &mut Context,
+    event: &Map<String, Value>,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> Result<bool, Box<dyn Error>> {
+    let brand = ctx.brand.clone();
+    let mut inspection_logged = false;
+    if let Some(Value::String(compiler_msg)) = event.get("reason") {
+        match compiler_msg.as_ref() {
+            "timing-info" => {
+                parse_timing_info(ctx, event, out, err)?;
+            }
+            "build-script-executed" => {
+                if let Some(Value::String(package_id)) = event.get("package_id") {
+                    // Shame build scripts that run:
+                    writeln!(
+                        out,
+                        "Running build script for {}",
+                        tidy_package_id(package_id)
+                    )?;
+                }
+            }
+            "compiler-artifact" => {
+                let fresh = if let Some(Value::Bool(fresh)) = event.get("fresh") {
+                    *fresh
+                } else {
+                    false
+                };
+                if let Some(Value::String(executable)) = event.get("executable") {
+                    ctx.executables.push(executable.clone());
+                }
+                if let Some(Value::String(package_id)) = event.get("package_id") {
+                    // Shame build scripts that run:
+                    writeln!(
+                        out,
+                        "Compiling {} {}",
+                        tidy_package_id(package_id),
+                        if fresh { "[fresh]" } else { "" }
+                    )?;
+                }
+            }
+            "compiler-message" => {
+                if let Some(Value::Object(msg)) = event.get("message") {
+                    let package = if let Some(Value::String(package_id)) = event.get("package_id") {
+                        package_name(package_id)
+                    } else {
+                        "unknown"
+                    };
+                    if let Ok(true) = parse_compiler_message(ctx, package, msg, out, err) {
+                        inspection_logged = true;
+                    }
+                }
+            }
+            "build-finished" => {}
+            _ => {
+                writeln!(out, "{}", compiler_msg)?;
+                writeln!(out, "{:?}", event)?;
+            }
+        }
+    } else if let Some(Value::String(ttype)) = event.get("type") {
+        record_test_stats(ctx, ttype, event);
+        match ttype.as_ref() {
+            "suite" => match event.get("event") {
+                Some(Value::String(event_name)) if ctx.tap.is_some() => {
+                    let tap = ctx.tap.as_mut().unwrap();
+                    match event_name.as_ref() {
+                        "started" => tap.suite_started(&ctx.suite_name, event)?,
+                        "ok" => tap.suite_finished(true)?,
+                        "failed" => {
+                            inspection_logged = true;
+                            tap.suite_finished(false)?
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Value::String(event_name)) => match event_name.as_ref() {
+                    "started" => {
+                        writeln!(out, "##{}[testSuiteStarted name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
+                    }
+                    "ok" => {
+                        writeln!(out, "##{}[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
+                    }
+                    "failed" => {
+                        inspection_logged = true;
+                        writeln!(out, "##{}[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']", brand)?;
+                    }
+                    _ => {
+                        writeln!(out, "format unknown {:?}", event)?;
+                    }
+                },
+                _ => {
+                    writeln!(out, "format {:?}", event)?;
+                }
+            },
+            "bench" => {
+                let name = parse_name(event);
+
+                if let Some(Value::Number(median)) = event.get("median") {
+                    writeln!(
+                        out,
+                        "##{}[buildStatisticValue key='bench.{}.median' value='{:.6}']",
+                        brand,
+                        name,
+                        median.as_f64().unwrap()
+                    )?;
+                }
+                if let Some(Value::Number(devation)) = event.get("deviation") {
+                    writeln!(
+                        out,
+                        "##{}[buildStatisticValue key='bench.{}.deviation' value='{:.6}']",
+                        brand, name, devation
+                    )?;
+                }
+            }
+            "test" => match event.get("event") {
+                Some(Value::String(s)) => {
+                    return parse_test_event(ctx, s, event, out, err);
+                }
+                _ => {
+                    writeln!(out, "unhandled event - please report: {:?}", event)?;
+                }
+            },
+            _ => {
+                writeln!(out, "unhandled event - please report: {:?}", event)?;
+            }
+        }
+    }
+    Ok(inspection_logged)
+}
+
+fn parse_compiler_message(
+    ctx: &mut Context,
+    package: &str,
+    msg: &Map<String, Value>,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> Result<bool, Box<dyn Error>> {
+    if let Some(Value::String(level)) = msg.get("level") {
+        let level = if level.as_str() == "error: internal compiler error" {
+            "error".to_string()
+        } else {
+            level.to_string()
+        };
+        if level.as_str() == "warning" || level.as_str() == "error" {
+            let rendered = if let Some(Value::String(message)) = msg.get("rendered") {
+                message
+            } else {
+                ""
+            };
+            let log_message = &remap_ansi_palette(rendered);
+            let message = &strip_ansi(rendered);
+
+            //TODO ask jetbrains if there's a way we can embed html here as message could
+            // do with being monospaced.
+            // let message = "<pre>".to_string() + &message + "</pre>";
+
+            let (code, explanation) = if let Some(Value::Object(code)) = msg.get("code") {
+                let explanation = if let Some(Value::String(explanation)) = code.get("explanation")
+                {
+                    Some(explanation.as_str())
+                } else {
+                    None
+                };
+
+                if let Some(Value::String(code)) = code.get("code") {
+                    (code.as_ref(), explanation)
+                } else {
+                    ("other", explanation)
+                }
+            } else {
+                ("other", None)
+            };
+
+            let no_spans = vec![];
+            let spans = if let Some(Value::Array(spans)) = msg.get("spans") {
+                spans
+            } else {
+                &no_spans
+            };
+            // Macro expansions are walked back to the call site in the user's code.
+            let primary_spans: Vec<Span> = spans
+                .iter()
+                .filter(|span| span.get("is_primary") == Some(&Value::Bool(true)))
+                .filter_map(|span| parse_span(call_site(span), &ctx.roots))
+                .collect();
+            let main_span = spans
+                .iter()
+                .find(|span| span.get("is_primary") == Some(&Value::Bool(true)))
+                .or_else(|| spans.first())
+                .map(call_site);
+            let location = main_span.and_then(|span| parse_span(span, &ctx.roots));
+            let (file, line) = match &location {
+                Some(span) => (span.file.as_str(), span.line),
+                None => ("no_file", 0),
+            };
+            let kind = match main_span.and_then(|span| span.get("file_name")?.as_str()) {
+                Some(file_name) => ctx.roots.normalise(file_name).1,
+                None => SourceKind::Checkout,
+            };
+            if ctx.drop_external && level == "warning" && kind != SourceKind::Checkout {
+                writeln!(out, "{}", log_message)?;
+                return Ok(false);
+            }
+            let snippet = match main_span {
+                Some(Value::Object(span)) => span_text(span),
+                _ => String::new(),
+            };
+            let related = related_locations(msg, &ctx.roots);
+            let suggestions = machine_applicable_suggestions(msg, &ctx.roots);
+
+            let group =
+                implied_group(msg).or_else(|| ctx.lint_groups.group_of(code).map(str::to_string));
+            let lint = lint_info(code, group.as_deref(), explanation, &ctx.severities);
+
+            let mut is_new = true;
+            if !is_warning_summary(message) {
+                let short_message = if let Some(Value::String(short)) = msg.get("message") {
+                    short
+                } else {
+                    message
+                };
+                // The same warning comes out of each target (lib, bin, test...) of a crate.
+                let key = format!(
+                    "{}\0{}\0{}:{}:{}\0{}",
+                    code,
+                    level,
+                    file,
+                    line,
+                    location.as_ref().map_or(0, |span| span.column),
+                    short_message
+                );
+                if let Some(&index) = ctx.seen_diagnostics.get(&key) {
+                    ctx.diagnostics[index].targets += 1;
+                    return Ok(true);
+                }
+                ctx.seen_diagnostics.insert(key, ctx.diagnostics.len());
+                let mut diagnostic = Diagnostic {
+                    package: package.to_string(),
+                    code: code.to_string(),
+                    category: lint.category.clone(),
+                    level: level.clone(),
+                    message: short_message.to_string(),
+                    file: file.to_string(),
+                    line,
+                    snippet,
+                    primary_spans,
+                    targets: 1,
+                    is_new: true,
+                    suggestions: suggestions.clone(),
+                };
+                // Errors always need fixing so the baseline only holds warnings.
+                if let (Some(baseline), "warning") = (&mut ctx.baseline, level.as_str()) {
+                    is_new = baseline.classify(&diagnostic.fingerprint());
+                    diagnostic.is_new = is_new;
+                }
+                ctx.diagnostics.push(diagnostic);
+            }
+
+            if !is_new {
+                // Already in the baseline so it stays in the log but not in TeamCity.
+                if level == "error" {
+                    writeln!(err, "{}", log_message)?;
+                } else {
+                    writeln!(out, "{}", log_message)?;
+                }
+                return Ok(false);
+            }
+
+            let outcome = if is_warning_summary(message) {
+                Outcome::Ignore
+            } else {
+                match ctx
+                    .policy
+                    .get(code)
+                    .or_else(|| ctx.policy.get(&lint.category))
+                {
+                    // A policy can make an error fail the build but never hide it.
+                    Some(Outcome::Fail) => Outcome::Fail,
+                    _ if level == "error" => Outcome::BuildProblem,
+                    Some(outcome) => *outcome,
+                    None => Outcome::Inspection,
+                }
+            };
+            if outcome == Outcome::Fail {
+                ctx.policy_failed = true;
+            }
+            let problem = outcome == Outcome::BuildProblem || outcome == Outcome::Fail;
+            let inspection = outcome == Outcome::Inspection || (problem && level == "warning");
+
+            if problem {
+                writeln!(
+                    out,
+                    "##{}[buildProblem description='{}' identity='{}']",
+                    ctx.brand,
+                    escape_message(message),
+                    code
+                )?;
+            }
+            if inspection {
+                if ctx.inspection_types.insert(code.to_string()) {
+                    writeln!(
+                        out,
+                        "##{}[inspectionType id='{}' category='{}' name='{}' description='{}']",
+                        ctx.brand,
+                        code,
+                        lint.category,
+                        code,
+                        escape_message(&lint.description)
+                    )?;
+                }
+                let mut inspection_message = message.trim_end().to_string();
+                for suggestion in &suggestions {
+                    inspection_message.push_str(&format!(
+                        "\nfix: {}:{}:{}: replace with `{}`",
+                        suggestion.file, suggestion.line, suggestion.column, suggestion.replacement
+                    ));
+                }
+                write!(
+                    out,
+                    "##{}[inspection typeId='{}' message='{}' file='{}' line='{}' SEVERITY='{}'",
+                    ctx.brand,
+                    code,
+                    escape_message(&inspection_message),
+                    escape_message(file),
+                    line,
+                    lint.severity
+                )?;
+                if let Some(span) = &location {
+                    write!(
+                        out,
+                        " column='{}' line_end='{}' column_end='{}'",
+                        span.column, span.line_end, span.column_end
+                    )?;
+                }
+                if !related.is_empty() {
+                    write!(out, " related='{}'", escape_message(&related.join("\n")))?;
+                }
+                writeln!(out, "]")?;
+            }
+            if level == "error" {
+                writeln!(err, "{}", log_message)?;
+            } else {
+                writeln!(out, "{}", log_message)?;
+            }
+
+            //additional attribute='<additional attribute>'
+            Ok(problem || inspection)
+        // } else {
+        //     println!("unhandled msg: {:?}", msg);
+        //     Ok(false)
+        // }
+        } else {
+            writeln!(out, "unhandled message: {:?}", msg)?;
+            Ok(false)
+        }
+    } else {
+        writeln!(out, "{:?}", msg)?;
+        Ok(false)
+    }
+}
+
+fn parse_span(span: &Value, roots: &SourceRoots) -> Option<Span> {
+    let number = |key: &str| span.get(key).and_then(Value::as_u64);
+    Some(Span {
+        file: roots.normalise(span.get("file_name")?.as_str()?).0,
+        line: number("line_start")?,
+        column: number("column_start")?,
+        line_end: number("line_end")?,
+        column_end: number("column_end")?,
+        label: span
+            .get("label")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// Follows a span out of any macro expansions to the outermost call site.
+fn call_site(span: &Value) -> &Value {
+    let mut span = span;
+    while let Some(call_site) = span.get("expansion").and_then(|exp| exp.get("span")) {
+        span = call_site;
+    }
+    span
+}
+
+/// Secondary spans and child notes/helps as `file:line:column: text` lines.
+fn related_locations(msg: &Map<String, Value>, roots: &SourceRoots) -> Vec<String> {
+    let mut related = vec![];
+    if let Some(Value::Array(spans)) = msg.get("spans") {
+        for span in spans {
+            if span.get("is_primary") == Some(&Value::Bool(true)) {
+                continue;
+            }
+            if let Some(span) = parse_span(call_site(span), roots) {
+                related.push(format!(
+                    "{}:{}:{}: {}",
+                    span.file,
+                    span.line,
+                    span.column,
+                    span.label.as_deref().unwrap_or("")
+                ));
+            }
+        }
+    }
+    if let Some(Value::Array(children)) = msg.get("children") {
+        for child in children {
+            let level = child.get("level").and_then(Value::as_str).unwrap_or("note");
+            let message = child.get("message").and_then(Value::as_str).unwrap_or("");
+            let span = child
+                .get("spans")
+                .and_then(Value::as_array)
+                .and_then(|spans| spans.first())
+                .and_then(|span| parse_span(call_site(span), roots));
+            related.push(match span {
+                Some(span) => format!(
+                    "{}:{}:{}: {}: {}",
+                    span.file, span.line, span.column, level, message
+                ),
+                None => format!("{}: {}", level, message),
+            });
+        }
+    }
+    related
+}
+
+/// How a group of lints is presented as a TeamCity inspection category.
+struct LintGroup {
+    name: &'static str,
+    description: &'static str,
+    /// Default TeamCity inspection severity.
+    severity: &'static str,
+}
+
+/// The lint groups with their own description and severity.
+/// (Lints in other groups are still reported, just with the default severity.)
+const LINT_GROUPS: &[LintGroup] = &[
+    LintGroup {
+        name: "clippy::correctness",
+        description: "Code that is outright wrong or useless.",
+        severity: "ERROR",
+    },
+    LintGroup {
+        name: "clippy::suspicious",
+        description: "Code that is most likely wrong or useless.",
+        severity: "WARNING",
+    },
+    LintGroup {
+        name: "clippy::style",
+        description: "Code that should be written in a more idiomatic way.",
+        severity: "WEAK WARNING",
+    },
+    LintGroup {
+        name: "clippy::complexity",
+        description: "Code that does something simple but in a complex way.",
+        severity: "WEAK WARNING",
+    },
+    LintGroup {
+        name: "clippy::perf",
+        description: "Code that can be written to run faster.",
+        severity: "WARNING",
+    },
+    LintGroup {
+        name: "clippy::pedantic",
+        description: "Lints which are rather strict or might have false positives.",
+        severity: "INFO",
+    },
+    LintGroup {
+        name: "clippy::restriction",
+        description: "Lints that restrict the use of language and library features.",
+        severity: "INFO",
+    },
+    LintGroup {
+        name: "clippy::nursery",
+        description: "New lints that are still under development.",
+        severity: "INFO",
+    },
+    LintGroup {
+        name: "clippy::cargo",
+        description: "Lints for the cargo manifest.",
+        severity: "INFO",
+    },
+    LintGroup {
+        name: "unused",
+        description: "Code that is never used or has no effect.",
+        severity: "WARNING",
+    },
+    LintGroup {
+        name: "nonstandard_style",
+        description: "Names that don't follow the Rust naming conventions.",
+        severity: "WEAK WARNING",
+    },
+    LintGroup {
+        name: "future_incompatible",
+        description: "Code that will stop compiling in a future release.",
+        severity: "WARNING",
+    },
+];
+
+/// The group each lint is in, as listed by `clippy-driver -W help` (or `rustc -W help`)
+/// so that it's always in step with the toolchain.
+#[derive(Default)]
+struct LintGroups {
+    by_lint: HashMap<String, String>,
+}
+
+impl LintGroups {
+    fn detect() -> LintGroups {
+        ["clippy-driver", "rustc"]
+            .iter()
+            .find_map(|tool| {
+                let output = Command::new(tool)
+                    .args(["-W", "help"])
+                    .stderr(Stdio::null())
+                    .output()
+                    .ok()
+                    .filter(|output| output.status.success())?;
+                Some(LintGroups::parse(&String::from_utf8_lossy(&output.stdout)))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Reads the `Lint groups provided by ...` tables. `warnings` and `clippy::all`
+    /// hold everything so are skipped, and edition groups are only used for
+    /// lints that aren't in any other group.
+    fn parse(help: &str) -> LintGroups {
+        let mut groups: Vec<(String, Vec<String>)> = vec![];
+        let mut in_groups = false;
+        for line in help.lines() {
+            if line.starts_with("Lint ") {
+                in_groups = line.starts_with("Lint groups");
+                continue;
+            }
+            let line = line.trim();
+            if !in_groups
+                || line.is_empty()
+                || line.starts_with("name ")
+                || line.starts_with("----")
+            {
+                continue;
+            }
+            if let Some((name, lints)) = line.split_once(char::is_whitespace) {
+                let name = name.replace('-', "_");
+                if name != "warnings" && name != "clippy::all" {
+                    let lints = lints
+                        .split(',')
+                        .map(|lint| lint.trim().replace('-', "_"))
+                        .collect();
+                    groups.push((name, lints));
+                }
+            }
+        }
+        let mut by_lint = HashMap::new();
+        for edition_groups in &[false, true] {
+            for (name, lints) in &groups {
+                if name.starts_with("rust_20") == *edition_groups {
+                    for lint in lints {
+                        by_lint.entry(lint.clone()).or_insert_with(|| name.clone());
+                    }
+                }
+            }
+        }
+        LintGroups { by_lint }
+    }
+
+    fn group_of(&self, lint: &str) -> Option<&str> {
+        self.by_lint.get(lint).map(String::as_str)
+    }
+}
+
+/// The group named by a diagnostic's "`#[warn(clippy::x)]` implied by `#[warn(clippy::group)]`"
+/// (or "`-W clippy::x` implied by `-W clippy::group`") note.
+fn implied_group(msg: &Map<String, Value>) -> Option<String> {
+    msg.get("children")?.as_array()?.iter().find_map(|child| {
+        let message = child.get("message")?.as_str()?;
+        let implied_by = message.split("implied by ").nth(1)?.trim_matches('`');
+        let group = match implied_by.split_once('(') {
+            Some((_, group)) => group.trim_end_matches(")]"),
+            None => implied_by.split_whitespace().last()?,
+        };
+        if group == "warnings" || group == "clippy::all" {
+            None
+        } else {
+            Some(group.replace('-', "_"))
+        }
+    })
+}
+
+/// How a lint is presented to TeamCity.
+struct LintInfo {
+    category: String,
+    description: String,
+    severity: String,
+}
+
+/// Looks up the category (the lint's group), description (with a link to the docs) and
+/// severity of a lint or error code, applying any `--severity` overrides.
+fn lint_info(
+    code: &str,
+    group: Option<&str>,
+    explanation: Option<&str>,
+    overrides: &HashMap<String, String>,
+) -> LintInfo {
+    let (fallback_category, url) = if let Some(lint) = code.strip_prefix("clippy::") {
+        (
+            "clippy",
+            format!(
+                "https://rust-lang.github.io/rust-clippy/master/index.html#{}",
+                lint
+            ),
+        )
+    } else if is_error_code(code) {
+        (
+            "rustc",
+            format!("https://doc.rust-lang.org/error_codes/{}.html", code),
+        )
+    } else {
+        (
+            "rustc",
+            "https://doc.rust-lang.org/rustc/lints/listing/index.html".to_string(),
+        )
+    };
+
+    let category = group.unwrap_or(fallback_category);
+    let group = LINT_GROUPS.iter().find(|known| known.name == category);
+    let description = match (explanation, group) {
+        (Some(explanation), _) => explanation.to_string(),
+        (None, Some(group)) => format!("{} See {}", group.description, url),
+        (None, None) => format!("See {}", url),
+    };
+    let severity = overrides
+        .get(code)
+        .or_else(|| overrides.get(category))
+        .map(String::as_str)
+        .or_else(|| group.map(|group| group.severity))
+        .unwrap_or("WARNING");
+    LintInfo {
+        category: category.to_string(),
+        description,
+        severity: severity.to_string(),
+    }
+}
+
+/// What a diagnostic should do to the TeamCity build.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    /// Only appears in the build log.
+    Ignore,
+    Inspection,
+    BuildProblem,
+    /// A build problem that also fails the build step.
+    Fail,
+}
+
+/// Parses `lint=outcome,clippy::group=outcome` where outcome is
+/// `ignore`, `inspection`, `buildProblem` or `fail`.
+fn parse_policy(spec: &str) -> HashMap<String, Outcome> {
+    spec.split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(lint, outcome)| {
+            let outcome = match outcome.to_lowercase().as_str() {
+                "ignore" => Outcome::Ignore,
+                "inspection" => Outcome::Inspection,
+                "buildproblem" => Outcome::BuildProblem,
+                "fail" => Outcome::Fail,
+                _ => {
+                    eprintln!(
+                        "cargo-service-message: unknown policy outcome '{}'",
+                        outcome
+                    );
+                    return None;
+                }
+            };
+            Some((lint.to_string(), outcome))
+        })
+        .collect()
+}
+
+/// rustc error codes look like `E0308`.
+fn is_error_code(code: &str) -> bool {
+    code.len() > 1 && code.starts_with('E') && code[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Parses `lint=SEVERITY,clippy::group=SEVERITY` (`WEAK_WARNING` may be used for `WEAK WARNING`).
+fn parse_severities(spec: &str) -> HashMap<String, String> {
+    spec.split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(lint, severity)| {
+            let severity = severity.to_uppercase().replace('_', " ");
+            match severity.as_str() {
+                "ERROR" | "WARNING" | "WEAK WARNING" | "INFO" => Some((lint.to_string(), severity)),
+                _ => {
+                    eprintln!(
+                        "cargo-service-message: unknown inspection severity '{}'",
+                        severity
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The `MachineApplicable` suggestions from a message's spans and its children's spans.
+fn machine_applicable_suggestions(
+    msg: &Map<String, Value>,
+    roots: &SourceRoots,
+) -> Vec<Suggestion> {
+    let mut spans: Vec<&Value> = vec![];
+    if let Some(Value::Array(msg_spans)) = msg.get("spans") {
+        spans.extend(msg_spans);
+    }
+    if let Some(Value::Array(children)) = msg.get("children") {
+        for child in children {
+            if let Some(Value::Array(child_spans)) = child.get("spans") {
+                spans.extend(child_spans);
+            }
+        }
+    }
+    spans
+        .into_iter()
+        .filter(|span| {
+            span.get("suggestion_applicability").and_then(Value::as_str)
+                == Some("MachineApplicable")
+        })
+        .filter_map(|span| {
+            let file_name = span.get("file_name")?.as_str()?;
+            let (file, kind) = roots.normalise(file_name);
+            Some(Suggestion {
+                path: roots.resolve(file_name),
+                file,
+                kind,
+                line: span.get("line_start")?.as_u64()?,
+                column: span.get("column_start")?.as_u64()?,
+                byte_start: span.get("byte_start")?.as_u64()? as usize,
+                byte_end: span.get("byte_end")?.as_u64()? as usize,
+                replacement: span.get("suggested_replacement")?.as_str()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// A patch of all the machine applicable fixes to files in the checkout,
+/// suitable for `git apply` from the checkout root.
+fn fixes_patch(diagnostics: &[Diagnostic]) -> String {
+    // Each diagnostic's edits to a file go together or not at all.
+    let mut by_file: BTreeMap<&str, (&Path, Vec<Vec<Edit>>)> = BTreeMap::new();
+    for diag in diagnostics {
+        let mut edits_by_file: BTreeMap<&str, Vec<Edit>> = BTreeMap::new();
+        for suggestion in &diag.suggestions {
+            if suggestion.kind == SourceKind::Checkout {
+                let edit = (
+                    suggestion.byte_start,
+                    suggestion.byte_end,
+                    suggestion.replacement.as_str(),
+                );
+                edits_by_file
+                    .entry(&suggestion.file)
+                    .or_default()
+                    .push(edit);
+                by_file
+                    .entry(&suggestion.file)
+                    .or_insert_with(|| (&suggestion.path, vec![]));
+            }
+        }
+        for (file, edits) in edits_by_file {
+            by_file.get_mut(file).unwrap().1.push(edits);
+        }
+    }
+    let mut patch = String::new();
+    for (file, (path, suggestions)) in by_file {
+        if let Ok(original) = std::fs::read_to_string(path) {
+            patch.push_str(&unified_diff(file, &original, &suggestions));
+        }
+    }
+    patch
+}
+
+/// Replace bytes `.0..1` with `.2`.
+type Edit<'a> = (usize, usize, &'a str);
+
+/// Lines of context around each change.
+const DIFF_CONTEXT: usize = 3;
+
+/// Applies suggestions, each a set of (byte start, byte end, replacement) edits, to
+/// `original` and returns the change as a unified diff. A suggestion with an edit
+/// that overlaps an earlier suggestion's is skipped as a whole.
+fn unified_diff(file: &str, original: &str, suggestions: &[Vec<Edit>]) -> String {
+    let conflict = |a: &Edit, b: &Edit| (a.0 < b.1 && b.0 < a.1) || (a.0, a.1) == (b.0, b.1);
+    let mut edits: Vec<Edit> = vec![];
+    for suggestion in suggestions {
+        let mut accepted: Vec<Edit> = vec![];
+        let applies = suggestion.iter().all(|edit| {
+            // The spans may be for a different version of the file.
+            if edit.0 > edit.1
+                || !original.is_char_boundary(edit.0)
+                || !original.is_char_boundary(edit.1)
+            {
+                return false;
+            }
+            if edits.contains(edit) || accepted.contains(edit) {
+                // Already applied for another diagnostic.
+                return true;
+            }
+            let clear = !edits
+                .iter()
+                .chain(&accepted)
+                .any(|other| conflict(edit, other));
+            accepted.push(*edit);
+            clear
+        });
+        if applies {
+            edits.extend(accepted);
+        }
+    }
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    if edits.is_empty() {
+        return String::new();
+    }
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", file, file);
+    if original.is_empty() {
+        let added: String = edits.iter().map(|edit| edit.2).collect();
+        if added.is_empty() {
+            return String::new();
+        }
+        let new_lines: Vec<&str> = added.split_inclusive('\n').collect();
+        diff.push_str(&format!("@@ -0,0 +1,{} @@\n", new_lines.len()));
+        for line in new_lines {
+            diff.push('+');
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        return diff;
+    }
+
+    let lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let mut line_starts = vec![];
+    let mut offset = 0;
+    for line in &lines {
+        line_starts.push(offset);
+        offset += line.len();
+    }
+    let line_of = |byte: usize| match line_starts.binary_search(&byte) {
+        Ok(line) => line,
+        Err(line) => line.saturating_sub(1),
+    };
+
+    // Group edits whose context would overlap into one hunk of (first line, last line, edits).
+    let mut hunks: Vec<(usize, usize, Vec<Edit>)> = vec![];
+    for edit in edits {
+        let first = line_of(edit.0);
+        let last = line_of(edit.1.max(edit.0 + 1) - 1).max(first);
+        match hunks.last_mut() {
+            Some(hunk) if first <= hunk.1 + 2 * DIFF_CONTEXT + 1 => {
+                hunk.1 = hunk.1.max(last);
+                hunk.2.push(edit);
+            }
+            _ => hunks.push((first, last, vec![edit])),
+        }
+    }
+
+    let mut delta: isize = 0;
+    for (first, last, edits) in hunks {
+        let start = line_starts[first];
+        let end = line_starts.get(last + 1).copied().unwrap_or(original.len());
+        let mut replaced = String::new();
+        let mut pos = start;
+        for (edit_start, edit_end, replacement) in edits {
+            replaced.push_str(&original[pos..edit_start]);
+            replaced.push_str(replacement);
+            pos = edit_end;
+        }
+        replaced.push_str(&original[pos..end]);
+        let new_lines: Vec<&str> = replaced.split_inclusive('\n').collect();
+
+        let before = first.saturating_sub(DIFF_CONTEXT);
+        let after = (last + 1 + DIFF_CONTEXT).min(lines.len());
+        let old_count = after - before;
+        let new_count = old_count - (last + 1 - first) + new_lines.len();
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            before + 1,
+            old_count,
+            (before as isize + 1 + delta),
+            new_count
+        ));
+        delta += new_count as isize - old_count as isize;
+
+        let mut push = |prefix: char, line: &str| {
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        };
+        for line in &lines[before..first] {
+            push(' ', line);
+        }
+        for line in &lines[first..=last] {
+            push('-', line);
+        }
+        for line in &new_lines {
+            push('+', line);
+        }
+        for line in &lines[last + 1..after] {
+            push(' ', line);
+        }
+    }
+    diff
+}
+
+/// rustc finishes with a "N warnings emitted" message that isn't a diagnostic in its own right.
+fn is_warning_summary(message: &str) -> bool {
+    message.contains("1 warning emitted") || message.contains(" warnings emitted")
+}
+
+/// The source lines covered by a span.
+fn span_text(span: &Map<String, Value>) -> String {
+    let mut text = String::new();
+    if let Some(Value::Array(lines)) = span.get("text") {
+        for line in lines {
+            if let Some(Value::String(line)) = line.get("text") {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    text
+}
+
+fn parse_test_event(
+    ctx: &mut Context,
+    event_type: &str,
+    event: &Map<String, Value>,
+    out: &mut dyn Write,
+    _err: &mut dyn Write,
+) -> Result<bool, Box<dyn Error>> {
+    //TODO split parsing from output!
+    let name = parse_name(event);
+
+    // (Ignored tests are still reported under bench as the TAP plan counts them.)
+    if let Some(tap) = &mut ctx.tap {
+        tap.test_event(event_type, &name, event)?;
+        return Ok(event_type == "failed");
+    }
+
+    match event_type {
+        "started" => {
+            writeln!(
+                out,
+                "##{}[flowStarted flowId='{}' parent='test_suite_flow_id']",
+                ctx.brand, name
+            )?;
+            writeln!(out, "##{}[testStarted flowId='{}' name='{}' captureStandardOutput='true' parent='test_suite_flow_id']", ctx.brand, name, name)?;
+            Ok(false)
+        }
+        "ok" => {
+            if let Some(Value::String(exec_time)) = event.get("exec_time") {
+                writeln!(
+                    out,
+                    "##{}[testFinished flowId='{}' name='{}' duration='{}']",
+                    ctx.brand, name, name, exec_time
+                )?;
+            } else {
+                writeln!(
+                    out,
+                    "##{}[testFinished flowId='{}' name='{}']",
+                    ctx.brand, name, name
+                )?;
+            }
+            writeln!(out, "##{}[flowFinished flowId='{}']", ctx.brand, name)?;
+            Ok(false)
+        }
+        "ignored" => {
+            // Bench ignores all the other tests. So if someone's running bench and test
+            // we don't want to return the results of the tests and say they're ignored
+            // - it's jolly confusing!
+            if ctx.cargo_cmd != "bench" {
+                writeln!(
+                    out,
+                    "##{}[testIgnored name='{}' message='ignore reason not yet available.']",
+                    ctx.brand, name
+                )?;
+            }
+            Ok(false)
+        }
+        "failed" => {
+            let stdout = if let Some(Value::String(stdout)) = event.get("stdout") {
+                stdout
+            } else {
+                ""
+            };
+            if let Some((left, right)) = find_comparison(stdout) {
+                writeln!(out, "##{}[testFailed type='comparisonFailure' name='{}' flowId='{}' message='test failed' details='{}' expected='{}' actual='{}']", ctx.brand, name, name, escape_message(stdout),
+                escape_message(left),escape_message(right))?;
+            } else {
+                writeln!(
+                    out,
+                    "##{}[testFailed name='{}' flowId='{}' message='test failed' details='{}']",
+                    ctx.brand,
+                    name,
+                    name,
+                    escape_message(stdout)
+                )?;
+            }
+
+            writeln!(
+                out,
+                "##{}[testFinished flowId='{}' name='{}']",
+                ctx.brand, name, name
+            )?;
+            writeln!(out, "##{}[flowFinished flowId='{}']", ctx.brand, name)?;
+            Ok(true)
+        }
+        _ => {
+            writeln!(out, "failed to parse {:?}", event)?;
+            Ok(false)
+        }
+    }
+}
+
+fn parse_timing_info(
+    ctx: &mut Context,
+    event: &Map<String, Value>,
+    out: &mut dyn Write,
+    _err: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let name = if let Some(Value::Object(target)) = event.get("target") {
+        if let Some(Value::String(target_name)) = target.get("name") {
+            target_name
+        } else {
+            "anon"
+        }
+    } else {
+        "anon"
+    };
+
+    let compile_mode = if let Some(Value::String(compile_mode)) = event.get("mode") {
+        compile_mode
+    } else {
+        "mode"
+    };
+
+    if let Some(Value::Number(duration)) = event.get("duration") {
+        if let Some(duration) = duration.as_f64() {
+            ctx.unit_times
+                .push((name.to_string(), compile_mode.to_string(), duration));
+            if duration > ctx.min_threshold {
+                writeln!(
+                    out,
+                    "##{}[buildStatisticValue key='{} {}' value='{:.6}']",
+                    ctx.brand, compile_mode, name, duration
+                )?;
+
+                writeln!(out, "Compiled {} in {:.2}s", name, duration)?;
+            }
+        }
+    }
+    Ok(())
+}
+/// Keeps track of what's needed for the run summary.
+fn record_test_stats(ctx: &mut Context, ttype: &str, event: &Map<String, Value>) {
+    let event_name = if let Some(Value::String(event_name)) = event.get("event") {
+        event_name.as_str()
+    } else {
+        return;
+    };
+    match (ttype, event_name) {
+        ("suite", "started") => ctx.suite_name = ctx.suite_names.next(),
+        ("suite", "ok") | ("suite", "failed") => {
+            let mut suite = Map::new();
+            suite.insert("name".to_string(), Value::from(ctx.suite_name.clone()));
+            for key in &["passed", "failed", "ignored", "measured", "filtered_out"] {
+                suite.insert(
+                    key.to_string(),
+                    event.get(*key).cloned().unwrap_or_else(|| Value::from(0)),
+                );
+            }
+            ctx.suites.push(Value::Object(suite));
+        }
+        ("test", "ok") | ("test", "failed") => {
+            if let Some(secs) = parse_exec_time(event) {
+                ctx.test_times.push((parse_name(event), secs));
+            }
+            if event_name == "failed" {
+                let stdout = if let Some(Value::String(stdout)) = event.get("stdout") {
+                    stdout
+                } else {
+                    ""
+                };
+                ctx.failures.push((parse_name(event), stdout.to_string()));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn parse_name(event: &Map<String, Value>) -> String {
+    let name = if let Some(Value::String(name)) = event.get("name") {
+        name
+    } else {
+        "no_name"
+    };
+    name.replace("::", ".")
+}
+
+/// Passes cargo's stderr through, sending on the names of the test binaries as cargo runs them.
+fn forward_suite_names(stderr: ChildStderr) -> (Receiver<String>, thread::JoinHandle<()>) {
+    let (names, received) = channel();
+    let forwarder = thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+            if let Some(name) = suite_name(&strip_ansi(&line)) {
+                let _ = names.send(name);
+            }
+        }
+    });
+    (received, forwarder)
+}
+
+/// Names libtest suites after the test binaries cargo said it was running.
+#[derive(Default)]
+struct SuiteNames {
+    received: Option<Receiver<String>>,
+    suites: usize,
+}
+
+impl SuiteNames {
+    /// The name of the suite that has just started.
+    fn next(&mut self) -> String {
+        self.suites += 1;
+        if let Some(received) = &self.received {
+            // A binary without the libtest harness has a "Running" line but no suite,
+            // so it's the latest binary that the suite belongs to.
+            let mut latest = received.try_iter().last();
+            if latest.is_none() {
+                // cargo says what it's running before running it, so the line will
+                // be along in a moment unless stderr isn't cargo's.
+                latest = received.recv_timeout(Duration::from_millis(200)).ok();
+                latest = received.try_iter().last().or(latest);
+            }
+            match latest {
+                Some(name) => return name,
+                None => self.received = None,
+            }
+        }
+        format!("rust_test_suite {}", self.suites)
+    }
+}
+
+/// The test suite name from cargo's `Running unittests src/lib.rs (target/debug/deps/foo-0123abcd)`
+/// (as `unittests src/lib.rs (foo)`) or `Doc-tests foo` lines.
+fn suite_name(line: &str) -> Option<String> {
+    let line = line.trim();
+    if let Some(running) = line.strip_prefix("Running ") {
+        Some(match running.rsplit_once(" (") {
+            Some((source, executable)) => {
+                let executable = Path::new(executable.trim_end_matches(')'))
+                    .file_stem()
+                    .map_or(executable.to_string(), |stem| {
+                        stem.to_string_lossy().into_owned()
+                    });
+                // Leave out the hash so names stay the same from build to build.
+                let binary = match executable.rsplit_once('-') {
+                    Some((binary, hash)) if hash.chars().all(|c| c.is_ascii_hexdigit()) => binary,
+                    _ => &executable,
+                };
+                format!("{} ({})", source, binary)
+            }
+            None => running.to_string(),
+        })
+    } else {
+        line.strip_prefix("Doc-tests ")
+            .map(|krate| format!("doctests {}", krate))
+    }
+}
+
+/// Removes ANSI escape sequences (as found in `json-diagnostic-rendered-ansi` output).
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            if let Some('[') = chars.next() {
+                // Parameters up to and including the final byte (@ to ~).
+                for c in &mut chars {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Yellow on a white background is unreadable, so in the build log
+/// warnings are shown in orange instead.
+fn remap_ansi_palette(text: &str) -> String {
+    let mut remapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("\u{1b}[") {
+        remapped.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = match rest.find('m') {
+            Some(end) if rest[..end].chars().all(|c| c.is_ascii_digit() || c == ';') => end,
+            _ => {
+                remapped.push_str("\u{1b}[");
+                continue;
+            }
+        };
+        let mut params: Vec<&str> = vec![];
+        let mut codes = rest[..end].split(';');
+        while let Some(code) = codes.next() {
+            match code {
+                "33" => params.push("38;5;208"),
+                "93" => params.push("38;5;214"),
+                // 256 colour and rgb codes take further parameters that mustn't be remapped.
+                "38" | "48" => {
+                    params.push(code);
+                    match codes.next() {
+                        Some("5") => {
+                            params.push("5");
+                            params.extend(codes.next());
+                        }
+                        Some("2") => {
+                            params.push("2");
+                            params.extend(codes.by_ref().take(3));
+                        }
+                        other => params.extend(other),
+                    }
+                }
+                _ => params.push(code),
+            }
+        }
+        remapped.push_str("\u{1b}[");
+        remapped.push_str(&params.join(";"));
+        remapped.push('m');
+        rest = &rest[end + 1..];
+    }
+    remapped.push_str(rest);
+    remapped
+}
+
+fn escape_message(unescaped: &str) -> String {
+    //TODO:\uNNNN (unicode symbol with code 0xNNNN)  as |0xNNNN
+    unescaped
+        .replace("|", "||")
+        .replace("[", "|[")
+        .replace("]", "|]")
+        .replace("\n", "|n")
+        .replace("\r", "|r")
+        .replace("'", "|'")
+}
+
+fn escape_xml(unescaped: &str) -> String {
+    unescaped
+        .replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+        .replace("'", "&apos;")
+        .replace("\n", "&#10;")
+}
+
+fn tidy_package_id(package_id: &str) -> String {
+    package_id
+        .replace(
+            "(registry+https://github.com/rust-lang/crates.io-index)",
+            "",
+        )
+        .replace(
+            "(registry+https://github.com/rust-lang/crates.io-index.git)",
+            "",
+        )
+}
+
+/// The crate name from either the old `name version (source)` or
+/// the newer `source#name@version` package id formats.
+fn package_name(package_id: &str) -> &str {
+    if let Some((source, fragment)) = package_id.rsplit_once('#') {
+        match fragment.split_once('@') {
+            Some((name, _version)) => name,
+            None => source.rsplit('/').next().unwrap_or(source),
+        }
+    } else {
+        package_id.split(' ').next().unwrap_or(package_id)
+    }
+}
+
+fn contains(needle: &str, args: &[String]) -> bool {
+    args.iter().any(|x| x == needle)
+}
+
+fn find_comparison(msg: &str) -> Option<(&str, &str)> {
+    if let (Some(left_index), Some(right_index)) = (msg.find("left: `"), msg.find("right: `")) {
+        if let (Some(left_end), Some(right_end)) = (
+            msg[left_index..].find("`,"),
+            msg[right_index..].find("`', "),
+        ) {
+            let left = &msg[(left_index + "left: `".len())..(left_index + left_end)];
+            let right = &msg[(right_index + "right: `".len())..(right_index + right_end)];
+            return Some((left, right));
+        }
+    }
+    None
+}
+
+/// Covered out of total for one measure of coverage.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CoverageCount {
+    covered: u64,
+    total: u64,
+}
+
+impl CoverageCount {
+    fn missed(&self) -> u64 {
+        self.total - self.covered.min(self.total)
+    }
+
+    fn percent(&self) -> f64 {
+        if self.total == 0 {
+            0.
+        } else {
+            self.covered as f64 * 100. / self.total as f64
+        }
+    }
+}
+
+/// Coverage totals for the run.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Coverage {
+    lines: CoverageCount,
+    functions: CoverageCount,
+    branches: CoverageCount,
+    /// A file counts as covered if any of its lines are.
+    files: CoverageCount,
+    /// Line coverage of each source file.
+    by_file: Vec<(String, CoverageCount)>,
+    /// Line coverage of each workspace crate.
+    by_crate: Vec<(String, CoverageCount)>,
+    /// Coverage of the lines changed since `--cover-diff`'s base.
+    changed_lines: CoverageCount,
+}
+
+/// Totals from `llvm-cov export -format=text`.
+fn parse_llvm_cov(export: &str) -> Coverage {
+    let json = match serde_json::from_str::<Value>(export) {
+        Ok(json) => json,
+        Err(_) => return Coverage::default(),
+    };
+    let count = |summary: &Value| CoverageCount {
+        covered: summary["covered"].as_u64().unwrap_or(0),
+        total: summary["count"].as_u64().unwrap_or(0),
+    };
+    let totals = &json["data"][0]["totals"];
+    let files: Vec<&Value> = json["data"][0]["files"]
+        .as_array()
+        .map(|files| files.iter().collect())
+        .unwrap_or_default();
+    let by_file: Vec<(String, CoverageCount)> = files
+        .iter()
+        .filter_map(|file| {
+            Some((
+                file["filename"].as_str()?.to_string(),
+                count(&file["summary"]["lines"]),
+            ))
+        })
+        .collect();
+    Coverage {
+        lines: count(&totals["lines"]),
+        functions: count(&totals["functions"]),
+        branches: count(&totals["branches"]),
+        files: CoverageCount {
+            covered: by_file
+                .iter()
+                .filter(|(_, lines)| lines.covered > 0)
+                .count() as u64,
+            total: files.len() as u64,
+        },
+        by_file,
+        ..Coverage::default()
+    }
+}
+
+/// Walks a grcov covdir tree collecting the line coverage of each file.
+fn covdir_files(node: &Value, dir: &Path, files: &mut Vec<(String, CoverageCount)>) {
+    let children = match node["children"].as_object() {
+        Some(children) => children,
+        None => return,
+    };
+    for (name, child) in children {
+        let path = dir.join(name);
+        if child["children"].is_object() {
+            covdir_files(child, &path, files);
+        } else {
+            files.push((
+                path.to_string_lossy().into_owned(),
+                CoverageCount {
+                    covered: child["linesCovered"].as_u64().unwrap_or(0),
+                    total: child["linesTotal"].as_u64().unwrap_or(0),
+                },
+            ));
+        }
+    }
+}
+
+/// Totals from an lcov tracefile (using the per file summary records).
+fn parse_lcov(lcov: &str) -> Coverage {
+    let mut coverage = Coverage::default();
+    let mut file_lines_hit = 0;
+    for line in lcov.lines() {
+        let (record, value) = line.trim().split_once(':').unwrap_or((line.trim(), ""));
+        let value = value.parse::<u64>().unwrap_or(0);
+        match record {
+            "SF" => {
+                coverage.files.total += 1;
+                file_lines_hit = 0;
+            }
+            "LF" => coverage.lines.total += value,
+            "LH" => {
+                coverage.lines.covered += value;
+                file_lines_hit = value;
+            }
+            "FNF" => coverage.functions.total += value,
+            "FNH" => coverage.functions.covered += value,
+            "BRF" => coverage.branches.total += value,
+            "BRH" => coverage.branches.covered += value,
+            "end_of_record" if file_lines_hit > 0 => coverage.files.covered += 1,
+            _ => {}
+        }
+    }
+    coverage
+}
+
+/// Line totals from a grcov covdir report.
+fn parse_cov(cov: &str) -> CoverageCount {
+    let stream = Deserializer::from_str(cov);
+    for value in stream.into_iter() {
+        if let Ok(Value::Object(map)) = value {
+            let lcov = if let Some(Value::Number(num)) = map.get("linesCovered") {
+                num.as_i64().unwrap_or(0) as u64
+            } else {
+                0
+            };
+            let ltot = if let Some(Value::Number(num)) = map.get("linesTotal") {
+                num.as_i64().unwrap_or(0) as u64
+            } else {
+                0
+            };
+            return CoverageCount {
+                covered: lcov,
+                total: ltot,
+            };
+        }
+    }
+    CoverageCount::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //(Benche isn't stable still!)
+    //use test::Bencher;
+
+    //Bench not stable:
+    // #[bench]
+    // fn example_bench_add_two(b: &mut Bencher) {
+    //     b.iter(|| {
+    //         print!("hi");
+    //     });
+    // }
+
+    #[test]
+    fn test() {
+        assert!(cargo_service_message(vec!["path/to/bin".into()]).is_err());
+        assert!(cargo_service_message(vec!["path/to/bin".into(), "fred".to_string()]).is_err());
+        //assert_eq!(Ok(()), cargo_service_message(vec!["path/to/bin".into(),"test".to_string()]));
+    }
+
+    // #[test]
+    // fn test_a_failure_fails() {
+    //     assert_eq!("red", "green");
+    // }
+
+    #[test]
+    fn test_slow() {
+        std::thread::sleep(std::time::Duration::new(1, 0));
+    }
+
+    #[test]
+    fn test_fast() {
+        std::thread::sleep(std::time::Duration::new(0, 20));
+    }
+
+    //Spec: if we call something that fails we should pass on the error message.
+
+    #[test]
+    fn test_compare() {
+        let output = r#"
+        [tests.test_a_failure_fails] thread 'tests::test_a_failure_fails' panicked at 'assertion failed: `(left == right)`
+  left: `"red"`,
+ right: `"green"`', src/bin/cargo-service-message.rs:194:9
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+        "#;
+
+        assert_eq!(Some(("\"red\"", "\"green\"")), find_comparison(output));
+    }
+
+    #[test]
+    fn parse_coverage() {
+        let cov = r#"
+        {
+            "children": {
+            },
+            "coveragePercent": 8.86,
+            "linesCovered": 1067,
+            "linesMissed": 10975,
+            "linesTotal": 12042,
+            "name": ""
+        }
+        "#;
+        let lines = parse_cov(cov);
+        assert_eq!(format!("{:.2}", lines.percent()), "8.86");
+        assert_eq!(lines.covered, 1067);
+        assert_eq!(lines.missed(), 10975);
+        assert_eq!(lines.total, 12042);
+    }
+
+    /// Excerpt of `clippy-driver -W help`.
+    const LINT_HELP: &str = "
+Lint checks provided by rustc:
+
+                  name  default  meaning
+                  ----  -------  -------
+      unused-variables  warn     detect variables which are not used in any way
+
+Lint groups provided by rustc:
+
+                  name  sub-lints
+                  ----  ---------
+              warnings  all lints that are set to issue warnings
+      rust-2018-idioms  bare-trait-objects, unused-extern-crates
+                unused  unused-imports, unused-variables, unused-extern-crates
+
+Lint checks loaded by this crate:
+
+                  name  default  meaning
+                  ----  -------  -------
+        clippy::eq-op   deny     equal operands on both sides of a comparison
+
+Lint groups loaded by this crate:
+
+                  name  sub-lints
+                  ----  ---------
+           clippy::all  clippy::eq-op, clippy::needless-return
+   clippy::correctness  clippy::eq-op, clippy::almost-swapped
+         clippy::style  clippy::needless-return
+";
+
+    fn test_ctx() -> Context {
+        Context {
+            brand: "t".to_owned(),
+            min_threshold: 5.,
+            cargo_cmd: "test".to_owned(),
+            lint_groups: LintGroups::parse(LINT_HELP),
+            ..Context::default()
+        }
+    }
+
+    fn check(line: &str) -> (String, String) {
+        check_all(&mut test_ctx(), &[line])
+    }
+
+    fn check_all(ctx: &mut Context, lines: &[&str]) -> (String, String) {
+        let mut out = vec![];
+        let mut err = vec![];
+        for line in lines {
+            let stream = Deserializer::from_str(line);
+            if let Value::Object(event) = stream.into_iter().next().unwrap().unwrap() {
+                process(ctx, &event, &mut out, &mut err).unwrap();
+            } else {
+                panic!("expected a json object");
+            }
+        }
+
+        let out = String::from_utf8(out).unwrap().trim_end().to_string();
+        let err = String::from_utf8(err).unwrap().trim_end().to_string();
+        (out, err)
+    }
+
+    #[test]
+    #[ignore]
+    fn this_is_an_example_ignored_test() {
+        println!("You shouldn't see me unless you ran cargo test -- --ignored --nocapture");
+    }
+
+    #[test]
+    fn test_testsuite_start() {
+        assert_eq!(
+            check(r#"{ "type": "suite", "event": "started", "test_count": 3 }"#),
+            (
+                "##t[testSuiteStarted name='rust_test_suite' flowId='test_suite_flow_id']".into(),
+                "".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_testsuite_ok() {
+        assert_eq!(
+            check(
+                r#"{ "type": "suite", "event": "ok", "passed": 3, "failed": 0, "allowed_fail": 0, "ignored": 0, "measured": 0, "filtered_out": 0 }"#
+            ),
+            (
+                "##t[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']".into(),
+                "".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_test_started() {
+        assert_eq!(
+            check(r#"{ "type": "test", "event": "started", "name": "tests::test" }"#),
+            (
+                r#"##t[flowStarted flowId='tests.test' parent='test_suite_flow_id']
+##t[testStarted flowId='tests.test' name='tests.test' captureStandardOutput='true' parent='test_suite_flow_id']"#.into(),
+                "".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_test_success() {
+        assert_eq!(
+            check(r#"{ "type": "test", "event": "ok", "name": "tests::test_slow", "exec_time": "10.000s" }"#),
+            (
+                r#"##t[testFinished flowId='tests.test_slow' name='tests.test_slow' duration='10.000s']
+##t[flowFinished flowId='tests.test_slow']"#.into(),
+                "".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_test_bench() {
+        assert_eq!(
+            check(
+                r#"{ "type": "bench", "name": "tests::example_bench_add_two", "median": 57, "deviation": 9 }"#
+            ),
+            (r#"##t[buildStatisticValue key='bench.tests.example_bench_add_two.median' value='57.000000']
+##t[buildStatisticValue key='bench.tests.example_bench_add_two.deviation' value='9']"#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_ignored_test_does_nothing() {
+        //TODO: should we not signal there's an ignored test here?
+        assert_eq!(
+            check(r#"{"event": "ignored", "name": "tests::test_a_failure_fails", "type": "test"}"#),
+            (r#"##t[testIgnored name='tests.test_a_failure_fails' message='ignore reason not yet available.']"#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_built_crate() {
+        assert_eq!(
+            check(
+                r#"{"reason":"compiler-artifact","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":0,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/Users/gilescope/projects/rust-analyzer/target/debug/deps/libcfg_if-f4d43ad0e841b013.rlib","/Users/gilescope/projects/rust-analyzer/target/debug/deps/libcfg_if-f4d43ad0e841b013.rmeta"],"executable":null,"fresh":false}"#
+            ),
+            (r#"Compiling cfg-if 0.1.10"#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_timing_info_do_nothing_below_threshold() {
+        assert_eq!(
+            check(
+                r#"{"reason":"timing-info","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"mode":"build","duration":0.23798263500000005,"rmeta_time":0.20777650800000003}"#
+            ),
+            (r#""#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_timing_info_add_stats_when_above_threshold() {
+        assert_eq!(
+            check(
+                r#"{"reason":"timing-info","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"mode":"build","duration":20.2379826350000005,"rmeta_time":20.2077765080000003}"#
+            ),
+            (
+                r#"##t[buildStatisticValue key='build cfg-if' value='20.237983']
+Compiled cfg-if in 20.24s"#
+                    .into(),
+                "".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_build_finished() {
+        assert_eq!(
+            check(r#"{"reason":"build-finished","success":false}"#),
+            (r#""#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_build_script_message() {
+        assert_eq!(
+            check(
+                r#"{"reason":"build-script-executed","package_id":"libc 0.2.79 (registry+https://github.com/rust-lang/crates.io-index)","linked_libs":[],"linked_paths":[],"cfgs":["freebsd11","libc_priv_mod_use","libc_union","libc_const_size_of","libc_align","libc_core_cvoid","libc_packedN","libc_cfg_target_vendor"],"env":[],"out_dir":"/Users/gilescope/projects/cargo-service-message2/target/debug/build/libc-5d2ac53ab99800d7/out"}"#
+            ),
+            (r#"Running build script for libc 0.2.79"#.into(), "".into())
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_unused_var_warning() {
+        assert_eq!(
+            check(
+                r#"{"reason":"compiler-message","package_id":"cargo-service-message 0.1.4 (path+file:///Users/gilescope/projects/cargo-service-message2)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-service-message","src_path":"/Users/gilescope/projects/cargo-service-message2/src/bin/cargo-service-message.rs","edition":"2018","doctest":false,"test":true},"message":{"rendered":"warning: unused variable: `x`\n   --> src/bin/cargo-service-message.rs:326:9\n    |\n326 |     let x = \"\";\n    |         ^ help: if this is intentional, prefix it with an underscore: `_x`\n    |","children":[{"children":[],"code":null,"level":"note","message":"`#[warn(unused_variables)]` on by default","rendered":null,"spans":[]},{"children":[],"code":null,"level":"help","message":"if this is intentional, prefix it with an underscore","rendered":null,"spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":"_x","suggestion_applicability":"MachineApplicable","text":[{"highlight_end":10,"highlight_start":9,"text":"    let x = \"\";"}]}]}],"code":{"code":"unused_variables","explanation":null},"level":"warning","message":"unused variable: `x`","spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":10,"highlight_start":9,"text":"    let x = \"\";"}]}]}}"#
+            ),
+            (r###"##t[inspectionType id='unused_variables' category='unused' name='unused_variables' description='Code that is never used or has no effect. See https://doc.rust-lang.org/rustc/lints/listing/index.html']
+##t[inspection typeId='unused_variables' message='warning: unused variable: `x`|n   --> src/bin/cargo-service-message.rs:326:9|n    |||n326 ||     let x = "";|n    ||         ^ help: if this is intentional, prefix it with an underscore: `_x`|n    |||nfix: src/bin/cargo-service-message.rs:326:9: replace with `_x`' file='src/bin/cargo-service-message.rs' line='326' SEVERITY='WARNING' column='9' line_end='326' column_end='10' related='note: `#|[warn(unused_variables)|]` on by default|nsrc/bin/cargo-service-message.rs:326:9: help: if this is intentional, prefix it with an underscore']
+warning: unused variable: `x`
+   --> src/bin/cargo-service-message.rs:326:9
+    |
+326 |     let x = "";
+    |         ^ help: if this is intentional, prefix it with an underscore: `_x`
+    |"###.into(), "".into()) 
+        );
+    }
+
+    #[test]
+    fn test_compiler_message_error() {
+        assert_eq!(
+            check(
+                r###"{"reason":"compiler-message","package_id":"cargo-service-message 0.1.4 (path+file:///Users/gilescope/projects/cargo-service-message2)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-service-message","src_path":"/Users/gilescope/projects/cargo-service-message2/src/bin/cargo-service-message.rs","edition":"2018","doctest":false,"test":true},"message":{"rendered":"error: expected one of `!` or `::`, found `tests`\n   --> src/bin/cargo-service-message.rs:747:6\n    |\n747 | moXd tests {\n    |      ^^^^^ expected one of `!` or `::`\n\n","children":[],"code":null,"level":"error","message":"expected one of `!` or `::`, found `tests`","spans":[{"byte_end":25339,"byte_start":25334,"column_end":11,"column_start":6,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":"expected one of `!` or `::`","line_end":747,"line_start":747,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":11,"highlight_start":6,"text":"moXd tests {"}]}]}}"###
+            ),
+            (r###"##t[buildProblem description='error: expected one of `!` or `::`, found `tests`|n   --> src/bin/cargo-service-message.rs:747:6|n    |||n747 || moXd tests {|n    ||      ^^^^^ expected one of `!` or `::`|n|n' identity='other']"###.into(), 
+            r##"error: expected one of `!` or `::`, found `tests`
+   --> src/bin/cargo-service-message.rs:747:6
+    |
+747 | moXd tests {
+    |      ^^^^^ expected one of `!` or `::`"##.into()) //TODO:
+        );
+    }
+
+    #[test]
+    fn test_tap_output() {
+        let (names, received) = channel();
+        names
+            .send(
+                suite_name("     Running unittests src/lib.rs (target/debug/deps/foo-0123abcd)")
+                    .unwrap(),
+            )
+            .unwrap();
+        let mut ctx = Context {
+            tap: Some(Tap::default()),
+            suite_names: SuiteNames {
+                received: Some(received),
+                ..SuiteNames::default()
+            },
+            ..test_ctx()
+        };
+        let (out, _) = check_all(
+            &mut ctx,
+            &[
+                r#"{ "type": "suite", "event": "started", "test_count": 3 }"#,
+                r#"{ "type": "test", "event": "started", "name": "tests::a" }"#,
+                r#"{ "type": "test", "event": "ok", "name": "tests::a", "exec_time": 0.5 }"#,
+                r#"{ "type": "test", "event": "ignored", "name": "tests::b", "message": "slow" }"#,
+                r#"{ "type": "test", "event": "failed", "name": "tests::c", "stdout": "thread 'c' panicked at 'assertion failed: `(left == right)`\n  left: `1`,\n right: `2`', src/lib.rs:1:1\n" }"#,
+                r#"{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1 }"#,
+            ],
+        );
+        assert_eq!(out, "");
+        let tap = String::from_utf8(ctx.tap.unwrap().finish()).unwrap();
+        assert_eq!(
+            tap,
+            r#"TAP version 14
+# Subtest: unittests src/lib.rs (foo)
+    1..3
+    ok 1 - tests.a
+      ---
+      duration_ms: 500.000
+      ...
+    ok 2 - tests.b # SKIP slow
+    not ok 3 - tests.c
+      ---
+      message: 'test failed'
+      severity: fail
+      stdout: |-
+        thread 'c' panicked at 'assertion failed: `(left == right)`
+          left: `1`,
+         right: `2`', src/lib.rs:1:1
+      expected: '1'
+      actual: '2'
+      ...
+not ok 1 - unittests src/lib.rs (foo)
+1..1
+"#
+        );
+
+        assert_eq!(
+            suite_name(&strip_ansi("\u{1b}[1m\u{1b}[92m     Running\u{1b}[0m tests/it.rs (target/debug/deps/it-9f8e7d6c5b4a3210)")).as_deref(),
+            Some("tests/it.rs (it)")
+        );
+        assert_eq!(
+            suite_name("   Doc-tests foo").as_deref(),
+            Some("doctests foo")
+        );
+        assert_eq!(suite_name("   Compiling foo v0.1.0"), None);
+    }
+
+    #[test]
+    fn test_suite_names() {
+        let (names, received) = channel();
+        let mut suite_names = SuiteNames {
+            received: Some(received),
+            ..SuiteNames::default()
+        };
+        // `harness = false` binaries are run but have no suite.
+        names.send("tests/custom.rs (custom)".to_string()).unwrap();
+        names
+            .send("unittests src/lib.rs (foo)".to_string())
+            .unwrap();
+        assert_eq!(suite_names.next(), "unittests src/lib.rs (foo)");
+        names.send("doctests foo".to_string()).unwrap();
+        assert_eq!(suite_names.next(), "doctests foo");
+
+        // No more names so stop waiting for them.
+        assert_eq!(suite_names.next(), "rust_test_suite 3");
+        assert!(suite_names.received.is_none());
+        assert_eq!(SuiteNames::default().next(), "rust_test_suite 1");
+    }
+
+    const UNUSED_X: &str = r#"{"reason":"compiler-message","package_id":"tests 0.0.1 (path+file:///work/tests)","message":{"rendered":"warning: unused variable: `x`\n","children":[],"code":{"code":"unused_variables","explanation":null},"level":"warning","message":"unused variable: `x`","spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":10,"highlight_start":9,"text":"    let x = \"\";"}]}]}}"#;
+
+    #[test]
+    fn test_code_climate_report() {
+        let mut ctx = test_ctx();
+        let moved = UNUSED_X
+            .replace(r#""line_start":326"#, r#""line_start":400"#)
+            .replace(r#""text":"    let x"#, r#""text":"        let x"#);
+        check_all(&mut ctx, &[UNUSED_X, &moved]);
+        assert_eq!(ctx.diagnostics.len(), 2);
+        assert_eq!(
+            ctx.diagnostics[0].fingerprint(),
+            ctx.diagnostics[1].fingerprint()
+        );
+
+        let report: Value = serde_json::from_str(&code_climate_report(&ctx.diagnostics)).unwrap();
+        let issue = &report[0];
+        assert_eq!(issue["check_name"], "unused_variables");
+        assert_eq!(issue["description"], "unused variable: `x`");
+        assert_eq!(issue["severity"], "minor");
+        assert_eq!(issue["categories"], serde_json::json!(["Style"]));
+        assert_eq!(issue["location"]["path"], "src/lib.rs");
+        assert_eq!(issue["location"]["lines"]["begin"], 326);
+        assert_eq!(report[1]["location"]["lines"]["begin"], 400);
+
+        let eq_op = UNUSED_X.replace("unused_variables", "clippy::eq_op");
+        let slow = UNUSED_X.replace("unused_variables", "clippy::needless_return");
+        let mut ctx = Context {
+            lint_groups: LintGroups::parse(&LINT_HELP.replace("clippy::style", "clippy::perf")),
+            ..test_ctx()
+        };
+        check_all(&mut ctx, &[&eq_op, &slow]);
+        let report: Value = serde_json::from_str(&code_climate_report(&ctx.diagnostics)).unwrap();
+        assert_eq!(report[0]["categories"], serde_json::json!(["Bug Risk"]));
+        assert_eq!(report[1]["categories"], serde_json::json!(["Performance"]));
+    }
+
+    #[test]
+    fn test_param() {
+        let params = "--debug --codeclimate --checkstyle=out/cs.xml";
+        assert_eq!(param(params, "--codeclimate"), Some(""));
+        assert_eq!(param(params, "--checkstyle"), Some("out/cs.xml"));
+        assert_eq!(param(params, "--cover"), None);
+    }
+
+    #[test]
+    fn test_checkstyle_report() {
+        let mut ctx = test_ctx();
+        check_all(&mut ctx, &[UNUSED_X]);
+        assert_eq!(
+            checkstyle_report(&ctx.diagnostics),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+  <file name="src/lib.rs">
+    <error line="326" column="9" severity="warning" message="unused variable: `x`" source="unused_variables"/>
+  </file>
+</checkstyle>
+"#
+        );
+    }
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(
+            package_name("cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)"),
+            "cfg-if"
+        );
+        assert_eq!(
+            package_name("registry+https://github.com/rust-lang/crates.io-index#cfg-if@0.1.10"),
+            "cfg-if"
+        );
+        assert_eq!(package_name("path+file:///work/tests#0.0.1"), "tests");
+        assert_eq!(package_name("path+file:///work/foo#bar@0.1.0"), "bar");
+    }
+
+    #[test]
+    fn test_cargo_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            cargo_args(&args(&["test", "--release"]), false),
+            args(&[
+                "test",
+                "--release",
+                "--message-format=json",
+                "-Ztimings=json,html,info",
+                "--",
+                "-Zunstable-options",
+                "--format",
+                "json",
+                "--report-time"
+            ])
+        );
+        assert_eq!(
+            cargo_args(&args(&["bench", "--", "--report-time"]), true),
+            args(&[
+                "bench",
+                "--",
+                "--report-time",
+                "--message-format=json-diagnostic-rendered-ansi",
+                "-Ztimings=json,html,info",
+                "-Zunstable-options",
+                "--format",
+                "json"
+            ])
+        );
+        assert_eq!(cargo_args(&args(&["fmt"]), true), args(&["fmt", "--"]));
+    }
+
+    #[test]
+    fn test_summary_json() {
+        let (names, received) = channel();
+        names
+            .send("unittests src/lib.rs (tests)".to_string())
+            .unwrap();
+        let mut ctx = Context {
+            suite_names: SuiteNames {
+                received: Some(received),
+                ..SuiteNames::default()
+            },
+            ..test_ctx()
+        };
+        check_all(
+            &mut ctx,
+            &[
+                UNUSED_X,
+                r#"{ "type": "suite", "event": "started", "test_count": 2 }"#,
+                r#"{ "type": "test", "event": "ok", "name": "tests::fast", "exec_time": 0.1 }"#,
+                r#"{ "type": "test", "event": "ok", "name": "tests::slow", "exec_time": 2.5 }"#,
+                r#"{ "type": "suite", "event": "ok", "passed": 2, "failed": 0, "ignored": 0, "measured": 0, "filtered_out": 1 }"#,
+            ],
+        );
+        let summary = summary_json(
+            &ctx,
+            &["test".to_string()],
+            "rustc 1.50.0",
+            0,
+            Some(&Coverage {
+                lines: CoverageCount {
+                    covered: 1,
+                    total: 2,
+                },
+                ..Coverage::default()
+            }),
+        );
+        assert_eq!(summary["version"], SUMMARY_VERSION);
+        assert_eq!(summary["command"][0], "test");
+        assert_eq!(summary["suites"][0]["name"], "unittests src/lib.rs (tests)");
+        assert_eq!(summary["suites"][0]["passed"], 2);
+        assert_eq!(summary["suites"][0]["filtered_out"], 1);
+        assert_eq!(summary["slowest_tests"][0]["name"], "tests.slow");
+        assert_eq!(summary["diagnostics"]["by_lint"]["unused_variables"], 1);
+        assert_eq!(summary["diagnostics"]["by_crate"]["tests"]["warning"], 1);
+        assert_eq!(summary["coverage"]["lines_total"], 2);
+    }
+
+    #[test]
+    fn test_markdown_report() {
+        let mut ctx = test_ctx();
+        check_all(
+            &mut ctx,
+            &[
+                UNUSED_X,
+                r#"{ "type": "test", "event": "failed", "name": "tests::broken", "stdout": "thread 'tests::broken' panicked at 'oops', src/lib.rs:3:5\n" }"#,
+            ],
+        );
+        assert_eq!(
+            markdown_report(
+                &ctx,
+                Some(&Coverage {
+                    lines: CoverageCount {
+                        covered: 1,
+                        total: 2
+                    },
+                    ..Coverage::default()
+                })
+            ),
+            r#"# cargo test summary
+
+## Failing tests
+
+### tests.broken
+
+```
+thread 'tests::broken' panicked at 'oops', src/lib.rs:3:5
+```
+
+## Warnings
+
+### unused_variables (1)
+
+* `src/lib.rs:326` unused variable: `x`
+
+## Coverage
+
+50.00% of lines covered (1 of 2)
+"#
+        );
+        assert!(html_report(&ctx, None)
+            .contains("<pre>thread &apos;tests::broken&apos; panicked at &apos;oops&apos;, src/lib.rs:3:5</pre>"));
+
+        assert_eq!(
+            md_code_block("assert `a` == ```b```"),
+            "````\nassert `a` == ```b```\n````"
+        );
+        assert_eq!(md_cell("a|b\nc"), "a\\|b c");
+    }
+
+    #[test]
+    fn test_macro_spans_point_at_call_site() {
+        let mut ctx = test_ctx();
+        let (out, _) = check_all(
+            &mut ctx,
+            &[
+                r#"{"reason":"compiler-message","package_id":"tests 0.0.1 (path+file:///work/tests)","message":{"rendered":"warning: oops\n","children":[],"code":{"code":"oops","explanation":null},"level":"warning","message":"oops","spans":[
+                {"file_name":"src/other.rs","is_primary":false,"line_start":3,"line_end":3,"column_start":1,"column_end":4,"label":"defined here","text":[],"expansion":null},
+                {"file_name":"/rustc/library/core/src/macros/mod.rs","is_primary":true,"line_start":10,"line_end":12,"column_start":1,"column_end":2,"label":null,"text":[],
+                 "expansion":{"macro_decl_name":"assert!","def_site_span":null,"span":{"file_name":"src/lib.rs","is_primary":false,"line_start":42,"line_end":42,"column_start":5,"column_end":20,"label":null,"text":[{"text":"    assert!(x);"}],"expansion":null}}}
+                ]}}"#,
+            ],
+        );
+        assert!(out.contains("file='src/lib.rs' line='42' SEVERITY='WARNING' column='5' line_end='42' column_end='20' related='src/other.rs:3:1: defined here'"), "{}", out);
+        let diag = &ctx.diagnostics[0];
+        assert_eq!(diag.snippet, "    assert!(x);\n");
+        assert_eq!(diag.primary_spans.len(), 1);
+        assert_eq!(diag.primary_spans[0].file, "src/lib.rs");
+    }
+
+    fn test_roots() -> SourceRoots {
+        SourceRoots {
+            workspace: PathBuf::from("/work/abc123/rust"),
+            checkout: PathBuf::from("/work/abc123"),
+            cargo_home: Some(PathBuf::from("/home/agent/.cargo")),
+            target: PathBuf::from("/work/abc123/rust/target"),
+            packages: vec![
+                ("rust".to_string(), PathBuf::from("/work/abc123/rust")),
+                (
+                    "macros".to_string(),
+                    PathBuf::from("/work/abc123/rust/macros"),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_normalise_paths() {
+        let roots = test_roots();
+        assert_eq!(
+            roots.normalise("src/lib.rs"),
+            ("rust/src/lib.rs".to_string(), SourceKind::Checkout)
+        );
+        assert_eq!(
+            roots.normalise("/work/abc123/other/src/lib.rs"),
+            ("other/src/lib.rs".to_string(), SourceKind::Checkout)
+        );
+        assert_eq!(
+            roots
+                .normalise("/home/agent/.cargo/registry/src/serde-1.0.0/src/lib.rs")
+                .1,
+            SourceKind::Registry
+        );
+        assert_eq!(
+            roots
+                .normalise("/work/abc123/rust/target/debug/build/x-1/out/gen.rs")
+                .1,
+            SourceKind::Generated
+        );
+        assert_eq!(
+            roots.normalise("/elsewhere/lib.rs"),
+            ("/elsewhere/lib.rs".to_string(), SourceKind::External)
+        );
+    }
+
+    #[test]
+    fn test_drop_external_warnings() {
+        let mut ctx = Context {
+            roots: test_roots(),
+            drop_external: true,
+            ..test_ctx()
+        };
+        let registry = UNUSED_X.replace(
+            r#""file_name":"src/lib.rs""#,
+            r#""file_name":"/home/agent/.cargo/registry/src/x/src/lib.rs""#,
+        );
+        let (out, _) = check_all(&mut ctx, &[UNUSED_X, &registry]);
+        assert_eq!(ctx.diagnostics.len(), 1);
+        assert_eq!(ctx.diagnostics[0].file, "rust/src/lib.rs");
+        assert_eq!(out.matches("##t[inspection ").count(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_diagnostics_reported_once() {
+        let mut ctx = test_ctx();
+        let test_target = UNUSED_X.replace(
+            r#""package_id""#,
+            r#""target":{"kind":["test"],"name":"tests"},"package_id""#,
+        );
+        let elsewhere = UNUSED_X.replace(r#""column_start":9"#, r#""column_start":15"#);
+        let (out, _) = check_all(&mut ctx, &[UNUSED_X, &test_target, &elsewhere]);
+        assert_eq!(out.matches("##t[inspection ").count(), 2);
+        assert_eq!(ctx.diagnostics.len(), 2);
+        assert_eq!(ctx.diagnostics[0].targets, 2);
+        assert_eq!(ctx.diagnostics[1].targets, 1);
+        assert!(markdown_report(&ctx, None).contains("unused variable: `x` (2 targets)"));
+        assert!(checkstyle_report(&ctx.diagnostics)
+            .contains("message=\"unused variable: `x` (2 targets)\""));
+        let report: Value = serde_json::from_str(&code_climate_report(&ctx.diagnostics)).unwrap();
+        assert_eq!(report[0]["description"], "unused variable: `x` (2 targets)");
+        assert_eq!(report[1]["description"], "unused variable: `x`");
+        let mut notes = vec![];
+        multi_target_notes(&ctx.diagnostics, &mut notes).unwrap();
+        assert_eq!(
+            String::from_utf8(notes).unwrap(),
+            "src/lib.rs:326: unused variable: `x` (2 targets)\n"
+        );
+    }
+
+    #[test]
+    fn test_lint_info() {
+        let overrides =
+            parse_severities("clippy::style=info,unused_variables=weak_warning,unused=bogus");
+        assert_eq!(overrides.len(), 2);
+
+        let lint = lint_info(
+            "clippy::needless_return",
+            Some("clippy::style"),
+            None,
+            &HashMap::new(),
+        );
+        assert_eq!(lint.category, "clippy::style");
+        assert_eq!(lint.severity, "WEAK WARNING");
+        assert!(lint.description.ends_with("index.html#needless_return"));
+        assert_eq!(
+            lint_info(
+                "clippy::needless_return",
+                Some("clippy::style"),
+                None,
+                &overrides
+            )
+            .severity,
+            "INFO"
+        );
+
+        let lint = lint_info(
+            "clippy::eq_op",
+            Some("clippy::correctness"),
+            None,
+            &overrides,
+        );
+        assert_eq!(lint.category, "clippy::correctness");
+        assert_eq!(lint.severity, "ERROR");
+
+        assert_eq!(
+            lint_info("clippy::not_in_a_group", None, None, &overrides).category,
+            "clippy"
+        );
+        assert_eq!(
+            lint_info("unused_variables", Some("unused"), None, &overrides).severity,
+            "WEAK WARNING"
+        );
 
-    #[test]
-    fn test() {
-        assert!(cargo_service_message(vec!["path/to/bin".into()]).is_err());
-        assert!(cargo_service_message(vec!["path/to/bin".into(), "fred".to_string()]).is_err());
-        //assert_eq!(Ok(()), cargo_service_message(vec!["path/to/bin".into(),"test".to_string()]));
+        let lint = lint_info(
+            "E0308",
+            None,
+            Some("Expected type did not match."),
+            &overrides,
+        );
+        assert_eq!(lint.category, "rustc");
+        assert_eq!(lint.description, "Expected type did not match.");
     }
 
-    // #[test]
-    // fn test_a_failure_fails() {
-    //     assert_eq!("red", "green");
-    // }
-
     #[test]
-    fn test_slow() {
-        std::thread::sleep(std::time::Duration::new(1, 0));
+    fn test_lint_groups() {
+        let groups = LintGroups::parse(LINT_HELP);
+        assert_eq!(groups.group_of("unused_variables"), Some("unused"));
+        assert_eq!(groups.group_of("unused_extern_crates"), Some("unused"));
+        assert_eq!(
+            groups.group_of("bare_trait_objects"),
+            Some("rust_2018_idioms")
+        );
+        assert_eq!(
+            groups.group_of("clippy::almost_swapped"),
+            Some("clippy::correctness")
+        );
+        assert_eq!(
+            groups.group_of("clippy::needless_return"),
+            Some("clippy::style")
+        );
+        assert_eq!(groups.group_of("clippy::eq-op"), None);
+
+        let note = |message: &str| -> Map<String, Value> {
+            serde_json::from_value(serde_json::json!({ "children": [{ "message": message }] }))
+                .unwrap()
+        };
+        assert_eq!(
+            implied_group(&note(
+                "`#[warn(clippy::doc_markdown)]` implied by `#[warn(clippy::pedantic)]`"
+            ))
+            .as_deref(),
+            Some("clippy::pedantic")
+        );
+        assert_eq!(
+            implied_group(&note(
+                "`-D clippy::needless-return` implied by `-D clippy::style`"
+            ))
+            .as_deref(),
+            Some("clippy::style")
+        );
+        assert_eq!(
+            implied_group(&note("`-D clippy::eq-op` implied by `-D warnings`")),
+            None
+        );
+        assert_eq!(
+            implied_group(&note("`#[warn(unused_variables)]` on by default")),
+            None
+        );
     }
 
     #[test]
-    fn test_fast() {
-        std::thread::sleep(std::time::Duration::new(0, 20));
+    fn test_inspection_type_declared_once() {
+        let mut ctx = test_ctx();
+        let y = UNUSED_X
+            .replace("`x`", "`y`")
+            .replace(r#""line_start":326"#, r#""line_start":327"#);
+        let (out, _) = check_all(&mut ctx, &[UNUSED_X, &y]);
+        assert_eq!(out.matches("##t[inspectionType ").count(), 1);
+        assert_eq!(out.matches("##t[inspection ").count(), 2);
     }
 
-    //Spec: if we call something that fails we should pass on the error message.
+    #[test]
+    fn test_inspection_file_escaped() {
+        let mut ctx = test_ctx();
+        let odd_path = UNUSED_X.replace("src/lib.rs", "src/it's|[odd].rs");
+        let (out, _) = check_all(&mut ctx, &[&odd_path]);
+        assert!(
+            out.contains("file='src/it|'s|||[odd|].rs' line='326' SEVERITY='WARNING'"),
+            "{}",
+            out
+        );
+    }
 
     #[test]
-    fn test_compare() {
-        let output = r#"
-        [tests.test_a_failure_fails] thread 'tests::test_a_failure_fails' panicked at 'assertion failed: `(left == right)`
-  left: `"red"`,
- right: `"green"`', src/bin/cargo-service-message.rs:194:9
-note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
-        "#;
+    fn test_baseline() {
+        let mut ctx = test_ctx();
+        check_all(&mut ctx, &[UNUSED_X]);
+        let dir = std::env::temp_dir().join(format!("service-message-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("baseline.json");
+        std::fs::write(&path, baseline_json(&ctx.diagnostics)).unwrap();
 
-        assert_eq!(Some(("\"red\"", "\"green\"")), find_comparison(output));
+        let mut ctx = Context {
+            baseline: Some(Baseline::load(&path).unwrap()),
+            ..test_ctx()
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+        let moved = UNUSED_X.replace(r#""line_start":326"#, r#""line_start":330"#);
+        let y = UNUSED_X
+            .replace("`x`", "`y`")
+            .replace(r#""text":"    let x"#, r#""text":"    let y"#);
+        let (out, _) = check_all(&mut ctx, &[&moved, &y]);
+        assert_eq!(out.matches("##t[inspection ").count(), 1);
+        assert!(out.contains("message='warning: unused variable: `y`"));
+
+        let baseline = ctx.baseline.as_ref().unwrap();
+        assert_eq!(
+            (baseline.new, baseline.existing, baseline.fixed()),
+            (1, 1, 0)
+        );
+        assert!(!ctx.diagnostics[0].is_new);
+        assert!(ctx.diagnostics[1].is_new);
+
+        // Errors are never baselined, even if an old baseline lists them.
+        let error = UNUSED_X.replace(r#""level":"warning""#, r#""level":"error""#);
+        let mut ctx = test_ctx();
+        check_all(&mut ctx, &[UNUSED_X, &error]);
+        let json: Value = serde_json::from_str(&baseline_json(&ctx.diagnostics)).unwrap();
+        assert_eq!(json["diagnostics"].as_array().unwrap().len(), 1);
+        let mut remaining = HashMap::new();
+        remaining.insert(ctx.diagnostics[1].fingerprint(), 1);
+        let mut ctx = Context {
+            baseline: Some(Baseline {
+                remaining,
+                ..Baseline::default()
+            }),
+            ..test_ctx()
+        };
+        let (out, _) = check_all(&mut ctx, &[&error]);
+        assert!(out.starts_with("##t[buildProblem "));
+
+        assert_eq!(
+            baseline_path("", Path::new("/work")),
+            Path::new("/work/service-message-baseline.json")
+        );
+        assert_eq!(
+            baseline_path("b.json", Path::new("/work")),
+            Path::new("b.json")
+        );
     }
 
     #[test]
-    fn parse_coverage() {
-        let cov = r#"
-        {
-            "children": {
-            },
-            "coveragePercent": 8.86,
-            "linesCovered": 1067,
-            "linesMissed": 10975,
-            "linesTotal": 12042,
-            "name": ""
-        }
-        "#;
-        let (percent, lcov, lmiss, ltot) = parse_cov(cov);
-        assert_eq!(percent, 8.86);
-        assert_eq!(lcov, 1067);
-        assert_eq!(lmiss, 10975);
-        assert_eq!(ltot, 12042);
-    }
+    fn test_policy() {
+        let mut ctx = Context {
+            policy: parse_policy("unused_variables=buildProblem,clippy::style=ignore"),
+            ..test_ctx()
+        };
+        let (out, _) = check_all(&mut ctx, &[UNUSED_X]);
+        assert!(out.starts_with("##t[buildProblem description='warning: unused variable: `x`|n' identity='unused_variables']"));
+        assert_eq!(out.matches("##t[inspection ").count(), 1);
+        assert!(!ctx.policy_failed);
 
-    fn check(line: &str) -> (String, String) {
-        let mut out = vec![];
-        let mut err = vec![];
-        let stream = Deserializer::from_str(&line);
-        if let Value::Object(event) = stream.into_iter().next().unwrap().unwrap() {
-            let ctx = Context {
-                debug: false,
-                brand: "t".to_owned(),
-                min_threshold: 5.,
-                cargo_cmd: "test".to_owned(),
-            };
+        let needless_return = UNUSED_X.replace("unused_variables", "clippy::needless_return");
+        let (out, _) = check_all(&mut ctx, &[&needless_return]);
+        assert_eq!(out, "warning: unused variable: `x`");
 
-            process(&ctx, &event, &mut out, &mut err).unwrap();
-        } else {
-            assert!(false);
-        }
+        let mut ctx = Context {
+            policy: parse_policy("unused=fail"),
+            ..test_ctx()
+        };
+        check_all(&mut ctx, &[UNUSED_X]);
+        assert!(ctx.policy_failed);
 
-        let out = String::from_utf8(out).unwrap().trim_end().to_string();
-        let err = String::from_utf8(err).unwrap().trim_end().to_string();
-        (out, err)
+        // Groups come from the toolchain, not a list of commonly seen lints.
+        let mut ctx = Context {
+            policy: parse_policy("clippy::correctness=fail"),
+            ..test_ctx()
+        };
+        let almost_swapped = UNUSED_X.replace("unused_variables", "clippy::almost_swapped");
+        let (out, _) = check_all(&mut ctx, &[&almost_swapped]);
+        assert!(out.starts_with("##t[buildProblem "));
+        assert!(ctx.policy_failed);
+
+        // Errors can't be ignored or downgraded to inspections.
+        let mut ctx = Context {
+            policy: parse_policy("E0308=ignore,unused=inspection"),
+            ..test_ctx()
+        };
+        let mismatched = UNUSED_X
+            .replace("unused_variables", "E0308")
+            .replace(r#""level":"warning""#, r#""level":"error""#);
+        let (out, _) = check_all(&mut ctx, &[&mismatched]);
+        assert!(out.starts_with("##t[buildProblem "));
+        let unused_error = UNUSED_X.replace(r#""level":"warning""#, r#""level":"error""#);
+        let (out, _) = check_all(&mut ctx, &[&unused_error]);
+        assert!(out.starts_with("##t[buildProblem "));
     }
 
     #[test]
-    #[ignore]
-    fn this_is_an_example_ignored_test() {
-        println!("You shouldn't see me unless you ran cargo test -- --ignored --nocapture");
+    fn test_unified_diff() {
+        let original = "fn main() {\n    let x = 1;\n    let y = 2;\n}\n";
+        let x = original.find("x =").unwrap();
+        let y = original.find("y =").unwrap();
+        assert_eq!(
+            unified_diff(
+                "src/main.rs",
+                original,
+                &[
+                    vec![(y, y + 1, "_y")],
+                    vec![(x, x + 1, "_x")],
+                    vec![(x, x + 1, "_x")]
+                ]
+            ),
+            "--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,4 +1,4 @@
+ fn main() {
+-    let x = 1;
+-    let y = 2;
++    let _x = 1;
++    let _y = 2;
+ }
+"
+        );
+
+        let long: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        let first = long.find("line 2\n").unwrap();
+        let second = long.find("line 18\n").unwrap();
+        assert_eq!(
+            unified_diff(
+                "a.txt",
+                &long,
+                &[vec![
+                    (first, first + 6, "two\nand a half"),
+                    (second, second + 7, "")
+                ]]
+            ),
+            "--- a/a.txt
++++ b/a.txt
+@@ -1,5 +1,6 @@
+ line 1
+-line 2
++two
++and a half
+ line 3
+ line 4
+ line 5
+@@ -15,6 +16,6 @@
+ line 15
+ line 16
+ line 17
+-line 18
++
+ line 19
+ line 20
+"
+        );
+
+        // The second suggestion clashes with the first on `x` so none of it is applied.
+        assert_eq!(
+            unified_diff(
+                "src/main.rs",
+                original,
+                &[
+                    vec![(x, x + 1, "_x")],
+                    vec![(y, y + 1, "_y"), (x, x + 5, "x: u8 = 1")]
+                ]
+            ),
+            "--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,4 +1,4 @@
+ fn main() {
+-    let x = 1;
++    let _x = 1;
+     let y = 2;
+ }
+"
+        );
+
+        assert_eq!(
+            unified_diff("src/lib.rs", "", &[vec![(0, 0, "fn main() {}\n")]]),
+            "--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -0,0 +1,1 @@
++fn main() {}
+"
+        );
+        assert_eq!(unified_diff("src/lib.rs", "", &[vec![(0, 1, "x")]]), "");
+
+        // A span that no longer lines up with the characters in the file.
+        assert_eq!(
+            unified_diff("src/lib.rs", "let é = 1;\n", &[vec![(5, 6, "e")]]),
+            ""
+        );
     }
 
     #[test]
-    fn test_testsuite_start() {
+    fn test_machine_applicable_suggestions() {
+        let msg: Value = serde_json::from_str(r#"{"children":[{"children":[],"code":null,"level":"help","message":"if this is intentional, prefix it with an underscore","rendered":null,"spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":"_x","suggestion_applicability":"MachineApplicable","text":[]}]},
+            {"children":[],"code":null,"level":"help","message":"maybe","rendered":null,"spans":[{"byte_end":10,"byte_start":1,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":null,"line_end":3,"line_start":3,"suggested_replacement":"y","suggestion_applicability":"MaybeIncorrect","text":[]}]}],"spans":[]}"#).unwrap();
+        let suggestions =
+            machine_applicable_suggestions(msg.as_object().unwrap(), &SourceRoots::default());
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].replacement, "_x");
         assert_eq!(
-            check(r#"{ "type": "suite", "event": "started", "test_count": 3 }"#),
-            (
-                "##t[testSuiteStarted name='rust_test_suite' flowId='test_suite_flow_id']".into(),
-                "".into()
-            )
+            (suggestions[0].byte_start, suggestions[0].byte_end),
+            (10931, 10932)
         );
+        assert_eq!((suggestions[0].line, suggestions[0].column), (326, 9));
     }
 
     #[test]
-    fn test_testsuite_ok() {
-        assert_eq!(
-            check(
-                r#"{ "type": "suite", "event": "ok", "passed": 3, "failed": 0, "allowed_fail": 0, "ignored": 0, "measured": 0, "filtered_out": 0 }"#
-            ),
-            (
-                "##t[testSuiteFinished name='rust_test_suite' flowId='test_suite_flow_id']".into(),
-                "".into()
-            )
+    fn test_ansi_rendered_messages() {
+        let mut ctx = test_ctx();
+        let coloured = UNUSED_X.replace(
+            r#""rendered":"warning: unused variable: `x`\n""#,
+            r#""rendered":"\u001b[0m\u001b[1m\u001b[33mwarning\u001b[0m\u001b[0m\u001b[1m: unused variable: `x`\u001b[0m\n""#,
+        );
+        let (out, _) = check_all(&mut ctx, &[&coloured]);
+        assert!(
+            out.contains("message='warning: unused variable: `x`'"),
+            "{}",
+            out
         );
+        assert!(out.ends_with(
+            "\u{1b}[0m\u{1b}[1m\u{1b}[38;5;208mwarning\u{1b}[0m\u{1b}[0m\u{1b}[1m: unused variable: `x`\u{1b}[0m"
+        ));
     }
 
     #[test]
-    fn test_test_started() {
+    fn test_remap_ansi_palette() {
+        assert_eq!(remap_ansi_palette("\u{1b}[1;33mw"), "\u{1b}[1;38;5;208mw");
+        assert_eq!(remap_ansi_palette("\u{1b}[38;5;33mb"), "\u{1b}[38;5;33mb");
         assert_eq!(
-            check(r#"{ "type": "test", "event": "started", "name": "tests::test" }"#),
-            (
-                r#"##t[flowStarted flowId='tests.test' parent='test_suite_flow_id']
-##t[testStarted flowId='tests.test' name='tests.test' captureStandardOutput='true' parent='test_suite_flow_id']"#.into(),
-                "".into()
-            )
+            remap_ansi_palette("\u{1b}[38;2;33;33;33;93mx"),
+            "\u{1b}[38;2;33;33;33;38;5;214mx"
         );
+        assert_eq!(strip_ansi("\u{1b}[1;33mwarning\u{1b}[0m: x"), "warning: x");
     }
 
     #[test]
-    fn test_test_success() {
+    fn test_diagnostic_statistics() {
+        let mut ctx = test_ctx();
+        let other_crate = UNUSED_X
+            .replace("tests 0.0.1", "other 0.0.1")
+            .replace("src/lib.rs", "other/src/lib.rs");
+        check_all(&mut ctx, &[UNUSED_X, &other_crate]);
+        let mut out = vec![];
+        diagnostic_statistics(&ctx, &mut out).unwrap();
         assert_eq!(
-            check(r#"{ "type": "test", "event": "ok", "name": "tests::test_slow", "exec_time": "10.000s" }"#),
-            (
-                r#"##t[testFinished flowId='tests.test_slow' name='tests.test_slow' duration='10.000s']
-##t[flowFinished flowId='tests.test_slow']"#.into(),
-                "".into()
-            )
+            String::from_utf8(out).unwrap(),
+            "##t[buildStatisticValue key='diagnostics.errors' value='0']
+##t[buildStatisticValue key='diagnostics.lint.unused_variables' value='2']
+##t[buildStatisticValue key='diagnostics.warnings' value='2']
+##t[buildStatisticValue key='diagnostics.warnings.crate.other' value='1']
+##t[buildStatisticValue key='diagnostics.warnings.crate.tests' value='1']
+"
         );
     }
 
     #[test]
-    fn test_test_bench() {
+    fn parse_llvm_cov_export() {
+        let export = r#"{"data":[{"files":[],"totals":{
+            "branches":{"count":10,"covered":4,"notcovered":6,"percent":40},
+            "functions":{"count":20,"covered":15,"percent":75},
+            "lines":{"count":200,"covered":150,"percent":75}}}],
+            "type":"llvm.coverage.json.export","version":"2.0.1"}"#;
+        let coverage = parse_llvm_cov(export);
+        assert_eq!(coverage.lines.percent(), 75.);
+        assert_eq!(coverage.lines.missed(), 50);
         assert_eq!(
-            check(
-                r#"{ "type": "bench", "name": "tests::example_bench_add_two", "median": 57, "deviation": 9 }"#
-            ),
-            (r#"##t[buildStatisticValue key='bench.tests.example_bench_add_two.median' value='57.000000']
-##t[buildStatisticValue key='bench.tests.example_bench_add_two.deviation' value='9']"#.into(), "".into())
+            coverage.functions,
+            CoverageCount {
+                covered: 15,
+                total: 20
+            }
         );
+        assert_eq!(coverage.branches.percent(), 40.);
+        assert_eq!(parse_llvm_cov("not json"), Coverage::default());
     }
 
     #[test]
-    fn test_ignored_test_does_nothing() {
-        //TODO: should we not signal there's an ignored test here?
+    fn test_coverage_breakdown() {
+        let covdir: Value = serde_json::from_str(
+            r#"{"children": {
+                "src": {"children": {
+                    "lib.rs": {"linesCovered": 3, "linesTotal": 4},
+                    "main.rs": {"linesCovered": 0, "linesTotal": 2}}},
+                "macros": {"children": {"src": {"children": {
+                    "lib.rs": {"linesCovered": 1, "linesTotal": 4}}}}}},
+                "linesCovered": 4, "linesTotal": 10}"#,
+        )
+        .unwrap();
+        let mut by_file = Vec::new();
+        covdir_files(&covdir, Path::new("/work/abc123/rust"), &mut by_file);
+        let coverage = coverage_breakdown(
+            Coverage {
+                by_file,
+                ..Coverage::default()
+            },
+            &test_roots(),
+        );
         assert_eq!(
-            check(r#"{"event": "ignored", "name": "tests::test_a_failure_fails", "type": "test"}"#),
-            (r#"##t[testIgnored name='tests.test_a_failure_fails' message='ignore reason not yet available.']"#.into(), "".into())
+            least_covered(&coverage.by_file)
+                .iter()
+                .map(|(file, _)| file.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "rust/src/main.rs",
+                "rust/macros/src/lib.rs",
+                "rust/src/lib.rs"
+            ]
+        );
+        assert_eq!(
+            coverage.by_crate,
+            [
+                (
+                    "macros".to_string(),
+                    CoverageCount {
+                        covered: 1,
+                        total: 4
+                    }
+                ),
+                (
+                    "rust".to_string(),
+                    CoverageCount {
+                        covered: 3,
+                        total: 6
+                    }
+                ),
+            ]
         );
+        assert!(coverage_statistics("t", &coverage).contains(
+            "##t[buildStatisticValue key='CodeCoverageL.crate.macros' value='25.000000']"
+        ));
     }
 
     #[test]
-    fn test_compiler_message_built_crate() {
+    fn test_coverage_failures() {
+        let minimums = parse_coverage_minimums("80,macros=50%,bogus=x,missing=10");
+        assert_eq!(minimums.len(), 3);
+        assert_eq!(minimums[""], 80.);
+
+        let lines = |covered, total| CoverageCount { covered, total };
+        let current: BTreeMap<String, CoverageCount> = [
+            (String::new(), lines(3, 4)),
+            ("macros".to_string(), lines(3, 5)),
+            ("rust".to_string(), lines(1, 3)),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let previous = parse_coverage_baseline(&serde_json::json!({
+            "total": { "covered": 3, "total": 4 },
+            "crates": { "macros": { "covered": 31, "total": 50 }, "rust": { "covered": 1, "total": 3 } }
+        }));
+        assert_eq!(previous["macros"], lines(31, 50));
         assert_eq!(
-            check(
-                r#"{"reason":"compiler-artifact","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":0,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/Users/gilescope/projects/rust-analyzer/target/debug/deps/libcfg_if-f4d43ad0e841b013.rlib","/Users/gilescope/projects/rust-analyzer/target/debug/deps/libcfg_if-f4d43ad0e841b013.rmeta"],"executable":null,"fresh":false}"#
-            ),
-            (r#"Compiling cfg-if 0.1.10"#.into(), "".into())
+            parse_coverage_baseline(&coverage_baseline_json(&previous)),
+            previous
+        );
+        assert_eq!(
+            coverage_failures(&current, &minimums, 1., &previous),
+            [
+                (
+                    "missing".to_string(),
+                    "no line coverage was found for missing which has a minimum of 10%".to_string()
+                ),
+                (
+                    "".to_string(),
+                    "line coverage is 75.00% which is below the minimum of 80%".to_string()
+                ),
+                (
+                    "macros".to_string(),
+                    "line coverage of macros dropped from 62.00% to 60.00% (more than the 1% allowed)"
+                        .to_string()
+                ),
+            ]
         );
+        assert!(coverage_failures(&current, &HashMap::new(), 2., &previous).is_empty());
+        // Unchanged coverage (1 of 3 is not exactly representable) never counts as a drop.
+        assert!(coverage_failures(&current, &HashMap::new(), 0., &current).is_empty());
     }
 
     #[test]
-    fn test_compiler_message_timing_info_do_nothing_below_threshold() {
+    fn test_diff_coverage() {
+        let diff = "diff --git a/rust/src/lib.rs b/rust/src/lib.rs
+index 1..2 100644
+--- a/rust/src/lib.rs
++++ b/rust/src/lib.rs
+@@ -3 +3,4 @@ fn a() {
+@@ -20,2 +23,0 @@ fn b() {
+@@ -30 +30 @@ fn c() {
+diff --git a/gone.rs b/gone.rs
+--- a/gone.rs
++++ /dev/null
+@@ -1,2 +0,0 @@
+";
+        let changed = parse_diff_lines(diff);
         assert_eq!(
-            check(
-                r#"{"reason":"timing-info","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"mode":"build","duration":0.23798263500000005,"rmeta_time":0.20777650800000003}"#
-            ),
-            (r#""#.into(), "".into())
+            changed["rust/src/lib.rs"]
+                .iter()
+                .copied()
+                .collect::<Vec<_>>(),
+            [3, 4, 5, 6, 30]
+        );
+        assert_eq!(changed.len(), 1);
+        let spaced = parse_diff_lines("+++ b/src/a b.rs\t\n@@ -0,0 +1 @@\n");
+        assert_eq!(spaced.keys().collect::<Vec<_>>(), ["src/a b.rs"]);
+
+        let lcov = "SF:src/lib.rs\nDA:3,1\nDA:4,0\nDA:5,0\nDA:30,0\nDA:31,0\nend_of_record\n";
+        let hits = lcov_line_hits(lcov, Path::new("/work/abc123/rust"), &test_roots());
+        let (count, uncovered) = diff_coverage(&changed, &hits);
+        assert_eq!(
+            count,
+            CoverageCount {
+                covered: 1,
+                total: 4
+            }
+        );
+        assert_eq!(
+            uncovered_inspections("t", &uncovered),
+            "##t[inspectionType id='uncovered-change' category='coverage' name='uncovered-change' description='Changed lines that are not covered by tests.']
+##t[inspection typeId='uncovered-change' message='lines 4-5 are not covered by tests' file='rust/src/lib.rs' line='4' SEVERITY='WARNING']
+##t[inspection typeId='uncovered-change' message='line 30 is not covered by tests' file='rust/src/lib.rs' line='30' SEVERITY='WARNING']
+"
         );
     }
 
     #[test]
-    fn test_compiler_message_timing_info_add_stats_when_above_threshold() {
+    fn test_cobertura_xml() {
+        let lcov = "SF:src/lib.rs\nDA:1,2\nBRDA:1,0,0,2\nBRDA:1,0,1,0\nBRDA:1,1,0,-\nDA:2,0\nend_of_record\nSF:macros/src/lib.rs\nDA:7,1\nend_of_record\n";
+        let dir = Path::new("/work/abc123/rust");
+        let hits = lcov_line_hits(lcov, dir, &test_roots());
+        let branches = lcov_branch_hits(lcov, dir, &test_roots());
         assert_eq!(
-            check(
-                r#"{"reason":"timing-info","package_id":"cfg-if 0.1.10 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cfg-if","src_path":"/Users/gilescope/.cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-0.1.10/src/lib.rs","edition":"2018","doctest":true,"test":true},"mode":"build","duration":20.2379826350000005,"rmeta_time":20.2077765080000003}"#
-            ),
-            (
-                r#"##t[buildStatisticValue key='build cfg-if' value='20.237983']
-Compiled cfg-if in 20.24s"#
-                    .into(),
-                "".into()
-            )
+            cobertura_xml(&hits, &branches, &test_roots(), 1700000000),
+            r#"<?xml version="1.0" ?>
+<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">
+<coverage lines-valid="3" lines-covered="2" line-rate="0.6667" branches-valid="3" branches-covered="1" branch-rate="0.3333" complexity="0" version="1.9" timestamp="1700000000">
+<sources>
+<source>/work/abc123</source>
+</sources>
+<packages>
+<package name="macros" line-rate="1.0000" branch-rate="0" complexity="0">
+<classes>
+<class name="lib.rs" filename="rust/macros/src/lib.rs" line-rate="1.0000" branch-rate="0" complexity="0">
+<methods/>
+<lines>
+<line number="7" hits="1" branch="false"/>
+</lines>
+</class>
+</classes>
+</package>
+<package name="rust" line-rate="0.5000" branch-rate="0.3333" complexity="0">
+<classes>
+<class name="lib.rs" filename="rust/src/lib.rs" line-rate="0.5000" branch-rate="0.3333" complexity="0">
+<methods/>
+<lines>
+<line number="1" hits="2" branch="true" condition-coverage="33% (1/3)"/>
+<line number="2" hits="0" branch="false"/>
+</lines>
+</class>
+</classes>
+</package>
+</packages>
+</coverage>
+"#
         );
     }
 
     #[test]
-    fn test_compiler_message_build_finished() {
+    fn test_coverage_options() {
+        let workspace = Path::new("/work");
+        let options = CoverageOptions::parse(
+            "--cover --cover-diff --cover-diff-min=75 --cover-min=80",
+            workspace,
+        );
+        assert!(options.engine == CoverageEngine::LlvmCov);
+        assert_eq!(options.diff_base.as_deref(), Some("origin/HEAD"));
+        assert_eq!(options.gate.diff_minimum, Some(75.));
+        assert_eq!(options.gate.minimums[""], 80.);
+        assert!(options.gate.baseline.is_none());
+
+        let options = CoverageOptions::parse(
+            "--cover-engine=grcov --cover-diff=develop --cover-baseline",
+            workspace,
+        );
+        assert!(options.engine == CoverageEngine::Grcov);
+        assert_eq!(options.diff_base.as_deref(), Some("develop"));
         assert_eq!(
-            check(r#"{"reason":"build-finished","success":false}"#),
-            (r#""#.into(), "".into())
+            options.gate.baseline,
+            Some(PathBuf::from("/work/service-message-coverage.json"))
+        );
+        assert_eq!(
+            CoverageOptions::parse("--cover-baseline=cov.json", workspace)
+                .gate
+                .baseline,
+            Some(PathBuf::from("cov.json"))
+        );
+
+        // Other --cover-* options on their own don't turn coverage on.
+        assert_eq!(
+            param("--cover-min=80 --cover-without-report", "--cover"),
+            None
         );
+        assert_eq!(param("--cover-min=80 --cover", "--cover"), Some(""));
     }
 
     #[test]
-    fn test_build_script_message() {
+    fn parse_lcov_tracefile() {
+        let lcov = "SF:src/lib.rs
+FN:1,a
+FNDA:1,a
+FNF:2
+FNH:1
+BRF:4
+BRH:3
+LF:10
+LH:5
+end_of_record
+SF:src/unused.rs
+FNF:1
+FNH:0
+LF:3
+LH:0
+end_of_record
+";
+        let coverage = parse_lcov(lcov);
         assert_eq!(
-            check(
-                r#"{"reason":"build-script-executed","package_id":"libc 0.2.79 (registry+https://github.com/rust-lang/crates.io-index)","linked_libs":[],"linked_paths":[],"cfgs":["freebsd11","libc_priv_mod_use","libc_union","libc_const_size_of","libc_align","libc_core_cvoid","libc_packedN","libc_cfg_target_vendor"],"env":[],"out_dir":"/Users/gilescope/projects/cargo-service-message2/target/debug/build/libc-5d2ac53ab99800d7/out"}"#
-            ),
-            (r#"Running build script for libc 0.2.79"#.into(), "".into())
+            coverage.lines,
+            CoverageCount {
+                covered: 5,
+                total: 13
+            }
+        );
+        assert_eq!(
+            coverage.functions,
+            CoverageCount {
+                covered: 1,
+                total: 3
+            }
+        );
+        assert_eq!(
+            coverage.branches,
+            CoverageCount {
+                covered: 3,
+                total: 4
+            }
+        );
+        assert_eq!(
+            coverage.files,
+            CoverageCount {
+                covered: 1,
+                total: 2
+            }
         );
     }
 
     #[test]
-    fn test_compiler_message_unused_var_warning() {
+    fn test_coverage_statistics() {
+        let coverage = Coverage {
+            lines: CoverageCount {
+                covered: 3,
+                total: 4,
+            },
+            functions: CoverageCount {
+                covered: 1,
+                total: 2,
+            },
+            ..Coverage::default()
+        };
         assert_eq!(
-            check(
-                r#"{"reason":"compiler-message","package_id":"cargo-service-message 0.1.4 (path+file:///Users/gilescope/projects/cargo-service-message2)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-service-message","src_path":"/Users/gilescope/projects/cargo-service-message2/src/bin/cargo-service-message.rs","edition":"2018","doctest":false,"test":true},"message":{"rendered":"warning: unused variable: `x`\n   --> src/bin/cargo-service-message.rs:326:9\n    |\n326 |     let x = \"\";\n    |         ^ help: if this is intentional, prefix it with an underscore: `_x`\n    |","children":[{"children":[],"code":null,"level":"note","message":"`#[warn(unused_variables)]` on by default","rendered":null,"spans":[]},{"children":[],"code":null,"level":"help","message":"if this is intentional, prefix it with an underscore","rendered":null,"spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":"_x","suggestion_applicability":"MachineApplicable","text":[{"highlight_end":10,"highlight_start":9,"text":"    let x = \"\";"}]}]}],"code":{"code":"unused_variables","explanation":null},"level":"warning","message":"unused variable: `x`","spans":[{"byte_end":10932,"byte_start":10931,"column_end":10,"column_start":9,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":null,"line_end":326,"line_start":326,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":10,"highlight_start":9,"text":"    let x = \"\";"}]}]}}"#
-            ),
-            (r###"##t[inspectionType id='unused_variables' category='warning' name='unused_variables' description='no explanation']
-##t[inspection typeId='unused_variables' message='warning: unused variable: `x`|n   --> src/bin/cargo-service-message.rs:326:9|n    |||n326 ||     let x = "";|n    ||         ^ help: if this is intentional, prefix it with an underscore: `_x`|n    ||' file='src/bin/cargo-service-message.rs' line='326' SEVERITY='warning']
-warning: unused variable: `x`
-   --> src/bin/cargo-service-message.rs:326:9
-    |
-326 |     let x = "";
-    |         ^ help: if this is intentional, prefix it with an underscore: `_x`
-    |"###.into(), "".into()) 
+            coverage_statistics("t", &coverage),
+            "##t[buildStatisticValue key='CodeCoverageL' value='75.000000']
+##t[buildStatisticValue key='CodeCoverageAbsLCovered' value='3.000000']
+##t[buildStatisticValue key='CodeCoverageAbsLTotal' value='4.000000']
+##t[buildStatisticValue key='CodeCoverageAbsLMissed' value='1.000000']
+##t[buildStatisticValue key='CodeCoverageM' value='50.000000']
+##t[buildStatisticValue key='CodeCoverageAbsMCovered' value='1.000000']
+##t[buildStatisticValue key='CodeCoverageAbsMTotal' value='2.000000']
+"
         );
     }
 
     #[test]
-    fn test_compiler_message_error() {
+    fn test_coverage_filter() {
+        assert!(glob_match("**/tests/**", "tests/it.rs"));
+        assert!(glob_match("**/tests/**", "macros/tests/a/it.rs"));
+        assert!(!glob_match("**/tests/**", "src/tests.rs"));
+        assert!(glob_match("src/*.rs", "src/lib.rs"));
+        assert!(!glob_match("src/*.rs", "src/a/lib.rs"));
+        assert!(glob_match("src/**.rs", "src/a/lib.rs"));
+        assert!(glob_match("src/l?b.rs", "src/lib.rs"));
+
+        let filter = CoverageFilter::parse(
+            "--cover --cover-exclude=src/generated/** --cover-include=src/**,macros/**",
+        );
+        assert!(filter.keeps("src/lib.rs"));
+        assert!(filter.keeps("macros/src/lib.rs"));
+        assert!(!filter.keeps("src/generated/bindings.rs"));
+        assert!(!filter.keeps("macros/examples/demo.rs"));
+        assert!(!filter.keeps("build.rs"));
+        assert!(CoverageFilter::parse("").keeps("build.rs"));
+
+        assert_eq!(glob_regex("**/tests/**"), "(.*/)?tests/.*");
+        assert_eq!(glob_regex("src/*.rs"), "src/[^/]*\\.rs");
+    }
+
+    #[test]
+    fn test_regex_escape() {
+        assert_eq!(regex_escape("/home/a.b/.cargo"), "/home/a\\.b/\\.cargo");
+    }
+
+    #[test]
+    fn test_start_coverage_run() {
+        let dir = std::env::temp_dir().join(format!("service-message-cov-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1-2.profraw"), "").unwrap();
+        std::fs::write(dir.join("pending"), "").unwrap();
+        let doctest = dir.join("doctests").join("src_lib_rs_10_0");
+        std::fs::create_dir_all(&doctest).unwrap();
+        std::fs::write(doctest.join("rust_out"), "").unwrap();
+        start_coverage_run(&dir).unwrap();
+        assert!(dir.join("1-2.profraw").exists());
         assert_eq!(
-            check(
-                r###"{"reason":"compiler-message","package_id":"cargo-service-message 0.1.4 (path+file:///Users/gilescope/projects/cargo-service-message2)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-service-message","src_path":"/Users/gilescope/projects/cargo-service-message2/src/bin/cargo-service-message.rs","edition":"2018","doctest":false,"test":true},"message":{"rendered":"error: expected one of `!` or `::`, found `tests`\n   --> src/bin/cargo-service-message.rs:747:6\n    |\n747 | moXd tests {\n    |      ^^^^^ expected one of `!` or `::`\n\n","children":[],"code":null,"level":"error","message":"expected one of `!` or `::`, found `tests`","spans":[{"byte_end":25339,"byte_start":25334,"column_end":11,"column_start":6,"expansion":null,"file_name":"src/bin/cargo-service-message.rs","is_primary":true,"label":"expected one of `!` or `::`","line_end":747,"line_start":747,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":11,"highlight_start":6,"text":"moXd tests {"}]}]}}"###
-            ),
-            (r###"##t[buildProblem description='error: expected one of `!` or `::`, found `tests`|n   --> src/bin/cargo-service-message.rs:747:6|n    |||n747 || moXd tests {|n    ||      ^^^^^ expected one of `!` or `::`|n|n' identity='other']"###.into(), 
-            r##"error: expected one of `!` or `::`, found `tests`
-   --> src/bin/cargo-service-message.rs:747:6
-    |
-747 | moXd tests {
-    |      ^^^^^ expected one of `!` or `::`"##.into()) //TODO:
+            doctest_objects(&dir.join("doctests")),
+            [doctest.join("rust_out").to_string_lossy()]
         );
+
+        std::fs::remove_file(dir.join("pending")).unwrap();
+        start_coverage_run(&dir).unwrap();
+        assert!(!dir.join("1-2.profraw").exists());
+        assert!(doctest_objects(&dir.join("doctests")).is_empty());
+
+        let old = dir.join("old-0123");
+        let new = dir.join("new-4567");
+        std::fs::write(&old, "").unwrap();
+        std::fs::write(&new, "").unwrap();
+        let object = |path: &Path| path.to_string_lossy().into_owned();
+        record_coverage_objects(&dir, &[object(&old)]).unwrap();
+        std::fs::remove_file(&old).unwrap();
+        record_coverage_objects(&dir, &[object(&new), object(&new)]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("objects.txt")).unwrap(),
+            object(&new)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rustc_version() {
+        assert!(has_check_cfg("rustc 1.80.0 (051478957 2024-07-21)"));
+        assert!(has_check_cfg("rustc 1.83.0-nightly (6c6d21008 2024-09-22)"));
+        assert!(!has_check_cfg("rustc 1.79.0 (129f3b996 2024-06-10)"));
+        assert!(!has_check_cfg(""));
+        assert!(is_nightly("rustc 1.83.0-nightly (6c6d21008 2024-09-22)"));
+        assert!(!is_nightly("rustc 1.80.0 (051478957 2024-07-21)"));
+    }
+
+    #[test]
+    fn test_coverage_session() {
+        let session = CoverageSession {
+            build: Some("proj/build#12".to_string()),
+            started: 1000,
+        };
+        assert_eq!(CoverageSession::parse(&session.to_string()), session);
+        assert!(session.is_current(Some("proj/build#12"), 1_000_000));
+        assert!(!session.is_current(Some("proj/build#13"), 1000));
+
+        let local = CoverageSession::parse("started=1000\n");
+        assert_eq!(local.build, None);
+        assert!(local.is_current(None, 2000));
+        assert!(local.is_current(Some("proj/build#13"), 2000));
+        assert!(!local.is_current(None, 1000 + COVERAGE_SESSION_HOURS * 60 * 60));
+        // The markers of older versions are empty.
+        assert!(!CoverageSession::parse("").is_current(None, unix_time()));
+
+        let dir =
+            std::env::temp_dir().join(format!("service-message-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(!active_coverage_session(&dir));
+        std::fs::write(dir.join("1-2.profraw"), "").unwrap();
+        std::fs::write(dir.join("session"), "started=1\n").unwrap();
+        assert!(!active_coverage_session(&dir));
+        assert!(!dir.join("session").exists());
+        assert!(!dir.join("1-2.profraw").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_program() {
+        let cargo = find_program("cargo").unwrap();
+        assert!(cargo.is_absolute());
+        assert_eq!(find_program(&cargo.to_string_lossy()), Some(cargo));
+        assert_eq!(find_program("no-such-program-for-service-message"), None);
+        assert_eq!(find_program("./no-such-program-for-service-message"), None);
     }
 }